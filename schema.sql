@@ -27,7 +27,8 @@ SET row_security = off;
 CREATE TABLE public.items (
     id bigint NOT NULL,
     user_id bigint,
-    message_id bigint
+    message_id bigint,
+    deleted_at timestamp without time zone
 );
 
 
@@ -83,7 +84,17 @@ CREATE TABLE public.messages (
     from_id bigint NOT NULL,
     chat_id bigint NOT NULL,
     text text,
-    created_at timestamp without time zone DEFAULT now() NOT NULL
+    created_at timestamp without time zone DEFAULT now() NOT NULL,
+    entities jsonb DEFAULT '[]'::jsonb NOT NULL,
+    link_preview jsonb,
+    action jsonb,
+    expires_at timestamp without time zone,
+    reply_to_id bigint,
+    forward_from_id bigint,
+    thread_id bigint,
+    reply_count integer DEFAULT 0 NOT NULL,
+    recent_repliers bigint[] DEFAULT '{}'::bigint[] NOT NULL,
+    views bigint DEFAULT 0 NOT NULL
 );
 
 
@@ -113,7 +124,14 @@ CREATE TABLE public.users (
     username text,
     first_name text NOT NULL,
     last_name text,
-    created_at timestamp without time zone DEFAULT now() NOT NULL
+    created_at timestamp without time zone DEFAULT now() NOT NULL,
+    photo_id bigint,
+    bio text,
+    birthday date,
+    website text,
+    status_text text,
+    status_emoji text,
+    last_seen_at timestamp without time zone
 );
 
 
@@ -222,6 +240,791 @@ ALTER TABLE ONLY public.known
     ADD CONSTRAINT user_id_fk FOREIGN KEY (user_id) REFERENCES public.items(id);
 
 
+--
+-- Name: mentions; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.mentions (
+    id bigint NOT NULL,
+    user_id bigint NOT NULL,
+    message_id bigint NOT NULL,
+    read boolean DEFAULT false NOT NULL,
+    created_at timestamp without time zone DEFAULT now() NOT NULL
+);
+
+
+--
+-- Name: mentions_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+ALTER TABLE public.mentions ALTER COLUMN id ADD GENERATED ALWAYS AS IDENTITY (
+    SEQUENCE NAME public.mentions_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1
+);
+
+
+--
+-- Name: mentions mentions_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.mentions
+    ADD CONSTRAINT mentions_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: mentions mentions_user_id_message_id_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.mentions
+    ADD CONSTRAINT mentions_user_id_message_id_key UNIQUE (user_id, message_id);
+
+
+--
+-- Name: mentions user_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.mentions
+    ADD CONSTRAINT user_id_fk FOREIGN KEY (user_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: mentions message_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.mentions
+    ADD CONSTRAINT message_id_fk FOREIGN KEY (message_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: scheduled_messages; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.scheduled_messages (
+    id bigint NOT NULL,
+    from_id bigint NOT NULL,
+    chat_id bigint NOT NULL,
+    text text NOT NULL,
+    entities jsonb DEFAULT '[]'::jsonb NOT NULL,
+    schedule_at timestamp without time zone NOT NULL,
+    created_at timestamp without time zone DEFAULT now() NOT NULL,
+    ttl integer,
+    reply_to_id bigint,
+    thread_id bigint,
+    status text DEFAULT 'pending'::text NOT NULL,
+    last_error text,
+    locked_until timestamp without time zone
+);
+
+
+--
+-- Name: scheduled_messages_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+ALTER TABLE public.scheduled_messages ALTER COLUMN id ADD GENERATED ALWAYS AS IDENTITY (
+    SEQUENCE NAME public.scheduled_messages_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1
+);
+
+
+--
+-- Name: scheduled_messages scheduled_messages_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.scheduled_messages
+    ADD CONSTRAINT scheduled_messages_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: scheduled_messages_status_schedule_at_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX scheduled_messages_status_schedule_at_idx ON public.scheduled_messages USING btree (status, schedule_at);
+
+
+--
+-- Name: scheduled_messages from_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.scheduled_messages
+    ADD CONSTRAINT from_id_fk FOREIGN KEY (from_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: scheduled_messages chat_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.scheduled_messages
+    ADD CONSTRAINT chat_id_fk FOREIGN KEY (chat_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: messages_expires_at_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX messages_expires_at_idx ON public.messages USING btree (expires_at) WHERE (expires_at IS NOT NULL);
+
+
+--
+-- Name: chat_timers; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.chat_timers (
+    id bigint NOT NULL,
+    first_id bigint NOT NULL,
+    second_id bigint NOT NULL,
+    ttl integer NOT NULL,
+    updated_at timestamp without time zone DEFAULT now() NOT NULL
+);
+
+
+--
+-- Name: chat_timers_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+ALTER TABLE public.chat_timers ALTER COLUMN id ADD GENERATED ALWAYS AS IDENTITY (
+    SEQUENCE NAME public.chat_timers_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1
+);
+
+
+--
+-- Name: chat_timers chat_timers_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.chat_timers
+    ADD CONSTRAINT chat_timers_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: chat_timers chat_timers_first_id_second_id_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.chat_timers
+    ADD CONSTRAINT chat_timers_first_id_second_id_key UNIQUE (first_id, second_id);
+
+
+--
+-- Name: chat_timers first_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.chat_timers
+    ADD CONSTRAINT first_id_fk FOREIGN KEY (first_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: chat_timers second_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.chat_timers
+    ADD CONSTRAINT second_id_fk FOREIGN KEY (second_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: messages reply_to_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.messages
+    ADD CONSTRAINT reply_to_id_fk FOREIGN KEY (reply_to_id) REFERENCES public.items(id) ON DELETE SET NULL;
+
+
+--
+-- Name: messages forward_from_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.messages
+    ADD CONSTRAINT forward_from_id_fk FOREIGN KEY (forward_from_id) REFERENCES public.items(id) ON DELETE SET NULL;
+
+
+--
+-- Name: messages thread_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.messages
+    ADD CONSTRAINT thread_id_fk FOREIGN KEY (thread_id) REFERENCES public.items(id) ON DELETE SET NULL;
+
+
+--
+-- Name: messages_thread_id_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX messages_thread_id_idx ON public.messages USING btree (thread_id) WHERE (thread_id IS NOT NULL);
+
+
+--
+-- Name: scheduled_messages thread_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.scheduled_messages
+    ADD CONSTRAINT thread_id_fk FOREIGN KEY (thread_id) REFERENCES public.items(id) ON DELETE SET NULL;
+
+
+--
+-- Name: scheduled_messages reply_to_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.scheduled_messages
+    ADD CONSTRAINT reply_to_id_fk FOREIGN KEY (reply_to_id) REFERENCES public.items(id) ON DELETE SET NULL;
+
+
+--
+-- Name: drafts; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.drafts (
+    id bigint NOT NULL,
+    user_id bigint NOT NULL,
+    chat_id bigint NOT NULL,
+    text text NOT NULL,
+    entities jsonb DEFAULT '[]'::jsonb NOT NULL,
+    reply_to_id bigint,
+    updated_at timestamp without time zone DEFAULT now() NOT NULL
+);
+
+
+--
+-- Name: drafts_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+ALTER TABLE public.drafts ALTER COLUMN id ADD GENERATED ALWAYS AS IDENTITY (
+    SEQUENCE NAME public.drafts_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1
+);
+
+
+--
+-- Name: drafts drafts_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.drafts
+    ADD CONSTRAINT drafts_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: drafts drafts_user_id_chat_id_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.drafts
+    ADD CONSTRAINT drafts_user_id_chat_id_key UNIQUE (user_id, chat_id);
+
+
+--
+-- Name: drafts user_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.drafts
+    ADD CONSTRAINT user_id_fk FOREIGN KEY (user_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: drafts chat_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.drafts
+    ADD CONSTRAINT chat_id_fk FOREIGN KEY (chat_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: drafts reply_to_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.drafts
+    ADD CONSTRAINT reply_to_id_fk FOREIGN KEY (reply_to_id) REFERENCES public.items(id) ON DELETE SET NULL;
+
+
+--
+-- Name: photos; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.photos (
+    id bigint NOT NULL,
+    user_id bigint NOT NULL,
+    created_at timestamp without time zone DEFAULT now() NOT NULL
+);
+
+
+--
+-- Name: photos_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+ALTER TABLE public.photos ALTER COLUMN id ADD GENERATED ALWAYS AS IDENTITY (
+    SEQUENCE NAME public.photos_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1
+);
+
+
+--
+-- Name: photos photos_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.photos
+    ADD CONSTRAINT photos_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: photos_user_id_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX photos_user_id_idx ON public.photos USING btree (user_id);
+
+
+--
+-- Name: photos user_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.photos
+    ADD CONSTRAINT user_id_fk FOREIGN KEY (user_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: users photo_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.users
+    ADD CONSTRAINT photo_id_fk FOREIGN KEY (photo_id) REFERENCES public.photos(id) ON DELETE SET NULL;
+
+
+--
+-- Name: privacy_rules; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.privacy_rules (
+    id bigint NOT NULL,
+    user_id bigint NOT NULL,
+    key text NOT NULL,
+    value text NOT NULL,
+    allow_ids bigint[] DEFAULT '{}'::bigint[] NOT NULL,
+    deny_ids bigint[] DEFAULT '{}'::bigint[] NOT NULL
+);
+
+
+--
+-- Name: privacy_rules_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+ALTER TABLE public.privacy_rules ALTER COLUMN id ADD GENERATED ALWAYS AS IDENTITY (
+    SEQUENCE NAME public.privacy_rules_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1
+);
+
+
+--
+-- Name: privacy_rules privacy_rules_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.privacy_rules
+    ADD CONSTRAINT privacy_rules_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: privacy_rules privacy_rules_user_id_key_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.privacy_rules
+    ADD CONSTRAINT privacy_rules_user_id_key_key UNIQUE (user_id, key);
+
+
+--
+-- Name: privacy_rules user_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.privacy_rules
+    ADD CONSTRAINT user_id_fk FOREIGN KEY (user_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: blocks; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.blocks (
+    id bigint NOT NULL,
+    user_id bigint NOT NULL,
+    blocked_id bigint NOT NULL,
+    created_at timestamp without time zone DEFAULT now() NOT NULL
+);
+
+
+--
+-- Name: blocks_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+ALTER TABLE public.blocks ALTER COLUMN id ADD GENERATED ALWAYS AS IDENTITY (
+    SEQUENCE NAME public.blocks_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1
+);
+
+
+--
+-- Name: blocks blocks_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.blocks
+    ADD CONSTRAINT blocks_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: blocks blocks_user_id_blocked_id_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.blocks
+    ADD CONSTRAINT blocks_user_id_blocked_id_key UNIQUE (user_id, blocked_id);
+
+
+--
+-- Name: blocks user_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.blocks
+    ADD CONSTRAINT user_id_fk FOREIGN KEY (user_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: blocks blocked_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.blocks
+    ADD CONSTRAINT blocked_id_fk FOREIGN KEY (blocked_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: contacts; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.contacts (
+    id bigint NOT NULL,
+    user_id bigint NOT NULL,
+    contact_id bigint NOT NULL,
+    first_name text NOT NULL,
+    last_name text,
+    created_at timestamp without time zone DEFAULT now() NOT NULL
+);
+
+
+--
+-- Name: contacts_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+ALTER TABLE public.contacts ALTER COLUMN id ADD GENERATED ALWAYS AS IDENTITY (
+    SEQUENCE NAME public.contacts_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1
+);
+
+
+--
+-- Name: contacts contacts_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.contacts
+    ADD CONSTRAINT contacts_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: contacts contacts_user_id_contact_id_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.contacts
+    ADD CONSTRAINT contacts_user_id_contact_id_key UNIQUE (user_id, contact_id);
+
+
+--
+-- Name: contacts_contact_id_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX contacts_contact_id_idx ON public.contacts USING btree (contact_id);
+
+
+--
+-- Name: contacts user_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.contacts
+    ADD CONSTRAINT user_id_fk FOREIGN KEY (user_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: contacts contact_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.contacts
+    ADD CONSTRAINT contact_id_fk FOREIGN KEY (contact_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: users users_email_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.users
+    ADD CONSTRAINT users_email_key UNIQUE (email);
+
+
+--
+-- Name: sessions; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.sessions (
+    id bigint NOT NULL,
+    user_id bigint NOT NULL,
+    device_name text,
+    ip text,
+    user_agent text,
+    created_at timestamp without time zone DEFAULT now() NOT NULL,
+    last_active_at timestamp without time zone DEFAULT now() NOT NULL
+);
+
+
+--
+-- Name: sessions_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+ALTER TABLE public.sessions ALTER COLUMN id ADD GENERATED ALWAYS AS IDENTITY (
+    SEQUENCE NAME public.sessions_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1
+);
+
+
+--
+-- Name: sessions sessions_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.sessions
+    ADD CONSTRAINT sessions_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: sessions_user_id_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX sessions_user_id_idx ON public.sessions USING btree (user_id);
+
+
+--
+-- Name: sessions user_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.sessions
+    ADD CONSTRAINT user_id_fk FOREIGN KEY (user_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: refresh_tokens; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.refresh_tokens (
+    id bigint NOT NULL,
+    session_id bigint NOT NULL,
+    token_hash text NOT NULL,
+    used_at timestamp without time zone,
+    expires_at timestamp without time zone NOT NULL,
+    created_at timestamp without time zone DEFAULT now() NOT NULL
+);
+
+
+--
+-- Name: refresh_tokens_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+ALTER TABLE public.refresh_tokens ALTER COLUMN id ADD GENERATED ALWAYS AS IDENTITY (
+    SEQUENCE NAME public.refresh_tokens_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1
+);
+
+
+--
+-- Name: refresh_tokens refresh_tokens_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.refresh_tokens
+    ADD CONSTRAINT refresh_tokens_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: refresh_tokens refresh_tokens_token_hash_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.refresh_tokens
+    ADD CONSTRAINT refresh_tokens_token_hash_key UNIQUE (token_hash);
+
+
+--
+-- Name: refresh_tokens_session_id_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX refresh_tokens_session_id_idx ON public.refresh_tokens USING btree (session_id);
+
+
+--
+-- Name: refresh_tokens session_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.refresh_tokens
+    ADD CONSTRAINT session_id_fk FOREIGN KEY (session_id) REFERENCES public.sessions(id) ON DELETE CASCADE;
+
+
+--
+-- Name: passwords; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.passwords (
+    id bigint NOT NULL,
+    user_id bigint NOT NULL,
+    password_hash text NOT NULL,
+    hint text,
+    recovery_email text,
+    created_at timestamp without time zone DEFAULT now() NOT NULL
+);
+
+
+--
+-- Name: passwords_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+ALTER TABLE public.passwords ALTER COLUMN id ADD GENERATED ALWAYS AS IDENTITY (
+    SEQUENCE NAME public.passwords_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1
+);
+
+
+--
+-- Name: passwords passwords_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.passwords
+    ADD CONSTRAINT passwords_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: passwords passwords_user_id_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.passwords
+    ADD CONSTRAINT passwords_user_id_key UNIQUE (user_id);
+
+
+--
+-- Name: passwords user_id_fk; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.passwords
+    ADD CONSTRAINT user_id_fk FOREIGN KEY (user_id) REFERENCES public.items(id) ON DELETE CASCADE;
+
+
+--
+-- Name: email_outbox; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.email_outbox (
+    id bigint NOT NULL,
+    recipient text NOT NULL,
+    subject text NOT NULL,
+    text_body text,
+    html_body text,
+    status text DEFAULT 'pending'::text NOT NULL,
+    attempts integer DEFAULT 0 NOT NULL,
+    last_error text,
+    next_attempt_at timestamp without time zone DEFAULT now() NOT NULL,
+    created_at timestamp without time zone DEFAULT now() NOT NULL,
+    sent_at timestamp without time zone,
+    expires_at timestamp without time zone
+);
+
+
+--
+-- Name: email_outbox_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+ALTER TABLE public.email_outbox ALTER COLUMN id ADD GENERATED ALWAYS AS IDENTITY (
+    SEQUENCE NAME public.email_outbox_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1
+);
+
+
+--
+-- Name: email_outbox email_outbox_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.email_outbox
+    ADD CONSTRAINT email_outbox_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: email_outbox_status_next_attempt_at_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX email_outbox_status_next_attempt_at_idx ON public.email_outbox USING btree (status, next_attempt_at);
+
+
+--
+-- Name: email_outbox_recipient_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX email_outbox_recipient_idx ON public.email_outbox USING btree (recipient);
+
+
+--
+-- Name: view_batches; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.view_batches (
+    id text NOT NULL,
+    applied_at timestamp without time zone DEFAULT now() NOT NULL
+);
+
+
+--
+-- Name: view_batches view_batches_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.view_batches
+    ADD CONSTRAINT view_batches_pkey PRIMARY KEY (id);
+
+
 -- Completed on 2025-03-29 12:38:01
 
 --
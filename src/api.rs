@@ -1,36 +1,122 @@
-use std::{convert::Infallible, sync::Arc};
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc};
 
 use axum::{
-    extract::State, http::StatusCode, response::{sse::Event, Sse}, routing::{get, patch, post}, Json, Router
+    extract::{ConnectInfo, DefaultBodyLimit, Multipart, Path, Query, State}, http::{header, HeaderMap, StatusCode}, response::{sse::Event, IntoResponse, Sse}, routing::{delete, get, patch, post, put}, Json, Router
 };
 use axum_auth::AuthBearer;
 use serde::{Deserialize, Serialize};
 use tokio_stream::{Stream, StreamExt};
 
-use crate::{db::Storage, models::{Message, User}, services::{email::ImplEmailService, events::{BackendEvent, EventService, ListenerPool}, message::{ImplMessageService, MessageRequest, MessageService, MessageServiceError}, user::{ImplUserService, PatchUserField, UserService, UserServiceError}}};
+use crate::{db::{NewSession, Storage}, entities::ParseMode, models::{BlockedUser, Contact, ContactName, Dialog, Draft, EmailDelivery, Message, MessageEntity, MessageViews, PasswordInfo, Photo, PrivacyKey, PrivacyRule, PrivacyValue, ScheduledMessage, Session, Thread, User, UserStatus}, random, services::{blob::{BlobStore, FileBlobStore}, contact::{ContactService, ContactServiceError, ImplContactService}, email::{EmailService, EmailServiceError, EmailTransport, FileEmailTransport, ImplEmailService, LogEmailTransport, SmtpEmailTransport, SmtpTls}, events::{BackendEvent, EventService, ListenerPool}, message::{ImplMessageService, MessageRequest, MessageService, MessageServiceError}, password::{ImplPasswordService, PasswordService, PasswordServiceError}, privacy::{ImplPrivacyService, PrivacyService, PrivacyServiceError}, presence::{ImplPresenceService, PresenceService, PresenceServiceError}, photo::{ImplPhotoService, PhotoService, PhotoServiceError, PhotoSize, MAX_PHOTO_SIZE}, preview::{FakeLinkPreviewFetcher, HttpLinkPreviewFetcher, LinkPreviewFetcher, PreviewService}, user::{ImplUserService, Login, OtpStatus, PatchUserField, Tokens, UserService, UserServiceError}}, workers};
 
 #[derive(Clone)]
 pub struct AppState {
     pub storage: Arc<Storage>,
     pub listener_pool: Arc<ListenerPool>,
+    pub link_preview_fetcher: Arc<dyn LinkPreviewFetcher + Send + Sync>,
+    pub blob_store: Arc<dyn BlobStore + Send + Sync>,
+    pub email_transport: Arc<dyn EmailTransport + Send + Sync>,
+}
+
+fn link_preview_fetcher() -> Arc<dyn LinkPreviewFetcher + Send + Sync> {
+    match std::env::var("LINK_PREVIEW_FETCHER").as_deref() {
+        Ok("fake") => Arc::new(FakeLinkPreviewFetcher::new(HashMap::new())),
+        _ => Arc::new(HttpLinkPreviewFetcher::new()),
+    }
+}
+
+fn email_transport() -> Arc<dyn EmailTransport + Send + Sync> {
+    match std::env::var("EMAIL_BACKEND").as_deref() {
+        Ok("smtp") => {
+            let host = std::env::var("SMTP_HOST").expect("SMTP_HOST must be set");
+            let tls = match std::env::var("SMTP_TLS").as_deref() {
+                Ok("implicit") => SmtpTls::Implicit,
+                Ok("starttls") | Err(_) => SmtpTls::StartTls,
+                Ok(tls) => panic!("unknown SMTP_TLS: {}", tls),
+            };
+            let default_port = if tls == SmtpTls::Implicit { 465 } else { 587 };
+            let port = std::env::var("SMTP_PORT").ok().and_then(|port| port.parse().ok()).unwrap_or(default_port);
+            let credentials = std::env::var("SMTP_USERNAME").ok().zip(std::env::var("SMTP_PASSWORD").ok());
+            let from = std::env::var("EMAIL_FROM").expect("EMAIL_FROM must be set");
+            Arc::new(SmtpEmailTransport::new(&host, port, tls, credentials, &from).expect("invalid SMTP settings"))
+        }
+        Ok("file") => Arc::new(FileEmailTransport::new(std::env::var("EMAIL_OUTBOX_DIR").unwrap_or_else(|_| "outbox".to_string()))),
+        Ok("log") | Err(_) => Arc::new(LogEmailTransport::new()),
+        Ok(backend) => panic!("unknown EMAIL_BACKEND: {}", backend),
+    }
 }
 
 pub async fn run() {
+    let state = AppState {
+        storage: Arc::new(Storage::new().await),
+        listener_pool: Arc::new(ListenerPool::new()),
+        link_preview_fetcher: link_preview_fetcher(),
+        blob_store: Arc::new(FileBlobStore::new(std::env::var("MEDIA_DIR").unwrap_or_else(|_| "media".to_string()))),
+        email_transport: email_transport(),
+    };
+    workers::spawn_scheduled_messages(state.clone());
+    workers::spawn_expired_messages_sweeper(state.clone());
+    workers::spawn_views_flusher(state.clone());
+    workers::spawn_email_outbox(state.clone());
+    workers::spawn_presence(state.clone());
+    workers::spawn_expired_sessions_sweeper(state.clone());
+
     let app = Router::new()
         .route("/api/v1/users/authenticate", post(try_auth_user))
+        .route("/api/v1/users/authenticate/resend", post(resend_otp))
         .route("/api/v1/users/token", post(get_token))
+        .route("/api/v1/users/token/refresh", post(refresh_token))
+        .route("/api/v1/users/token/password", post(verify_password))
+        .route("/api/v1/users/token/password/recovery", post(send_password_recovery))
+        .route("/api/v1/users/token/password/recovery/confirm", post(recover_password))
         .route("/api/v1/users/", post(create_user))
         .route("/api/v1/users/me", get(get_me))
         .route("/api/v1/users/me", patch(patch_me))
+        .route("/api/v1/users/me", delete(delete_me))
+        .route("/api/v1/users/me/email", post(request_email_change))
+        .route("/api/v1/users/me/password", get(get_password_info))
+        .route("/api/v1/users/me/password", put(set_password))
+        .route("/api/v1/users/me/password", delete(remove_password))
+        .route("/api/v1/users/me/password/email/confirm", post(confirm_recovery_email))
+        .route("/api/v1/users/me/email/confirm", post(confirm_email_change))
+        .route("/api/v1/users/me/photo", put(set_photo))
+        .route("/api/v1/users/me/photos", post(upload_photo).layer(DefaultBodyLimit::max(MAX_PHOTO_SIZE + 64 * 1024)))
+        .route("/api/v1/users/me/photos/{id}", delete(delete_photo))
+        .route("/api/v1/users/{id}/photos", get(get_photos))
+        .route("/api/v1/users/{id}/status", get(get_user_status))
+        .route("/api/v1/photos/{id}", get(get_photo_file))
+        .route("/api/v1/sessions/", get(get_sessions))
+        .route("/api/v1/sessions/", delete(terminate_other_sessions))
+        .route("/api/v1/sessions/{id}", delete(terminate_session))
         .route("/api/v1/messages/", post(send_message))
+        .route("/api/v1/messages/forward", post(forward_message))
+        .route("/api/v1/messages/views", post(mark_viewed))
+        .route("/api/v1/messages/{id}/thread", get(get_thread))
+        .route("/api/v1/chats/{chat_id}/scheduled", get(get_scheduled_messages))
+        .route("/api/v1/chats/{chat_id}/ttl", put(set_chat_ttl))
+        .route("/api/v1/chats/{chat_id}/draft", put(save_draft))
+        .route("/api/v1/chats/{chat_id}/draft", delete(clear_draft))
+        .route("/api/v1/dialogs/", get(get_dialogs))
+        .route("/api/v1/scheduled/{id}", patch(edit_scheduled_message))
+        .route("/api/v1/scheduled/{id}", delete(cancel_scheduled_message))
+        .route("/api/v1/scheduled/{id}/send", post(send_scheduled_message))
+        .route("/api/v1/contacts/", get(get_contacts))
+        .route("/api/v1/contacts/", post(add_contact))
+        .route("/api/v1/contacts/{user_id}", patch(rename_contact))
+        .route("/api/v1/contacts/{user_id}", delete(delete_contact))
+        .route("/api/v1/blocks/", get(get_blocked_users))
+        .route("/api/v1/blocks/", post(block_user))
+        .route("/api/v1/blocks/{user_id}", delete(unblock_user))
+        .route("/api/v1/privacy/", get(get_privacy_rules))
+        .route("/api/v1/privacy/{key}", put(set_privacy_rule))
+        .route("/api/v1/mentions/", get(get_mentions))
+        .route("/api/v1/mentions/read", post(read_mentions))
         .route("/api/v1/events/sse", get(get_events))
-        .with_state(AppState {
-            storage: Arc::new(Storage::new().await),
-            listener_pool: Arc::new(ListenerPool::new()),
-        });
+        .route("/api/v1/support/emails", get(get_email_deliveries))
+        .with_state(state);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
 
 #[derive(Deserialize, Serialize)]
@@ -43,24 +129,100 @@ pub struct AuthenticateRequest {
     pub email: String,
 }
 
-#[derive(Deserialize, Serialize)]
-pub struct AuthenticateResponse {
-    pub otp_hash: String,
-}
-
 impl From<UserServiceError> for (StatusCode, Json<Error>) {
     fn from(service_error: UserServiceError) -> Self {
         log::error!("User Service Error: {:?}", service_error);
         match service_error {
             UserServiceError::Storage(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
             UserServiceError::InvalidEmail => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid email".to_string() })),
+            UserServiceError::EmailUsed => (StatusCode::BAD_REQUEST, Json(Error { message: "email already used".to_string() })),
             UserServiceError::InvalidOTP => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid otp".to_string() })),
             UserServiceError::OTPNotSent => (StatusCode::BAD_REQUEST, Json(Error { message: "otp not sent".to_string() })),
+            UserServiceError::TooManyAttempts => (StatusCode::TOO_MANY_REQUESTS, Json(Error { message: "too many attempts".to_string() })),
+            UserServiceError::Photo(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            UserServiceError::Password(password_error) => password_error.into(),
             UserServiceError::Email(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
             UserServiceError::InvalidAuthentication => (StatusCode::UNAUTHORIZED, Json(Error { message: "invalid authentication".to_string() })),
             UserServiceError::UserAlreadyExists => (StatusCode::BAD_REQUEST, Json(Error { message: "user already exists".to_string() })),
             UserServiceError::UsernameUsed => (StatusCode::BAD_REQUEST, Json(Error { message: "username already used".to_string() })),
             UserServiceError::InvalidUsername => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid username".to_string() })),
+            UserServiceError::UnknownField(name) => (StatusCode::BAD_REQUEST, Json(Error { message: format!("unknown field: {}", name) })),
+            UserServiceError::InvalidField(name) => (StatusCode::BAD_REQUEST, Json(Error { message: format!("invalid field: {}", name) })),
+            UserServiceError::SessionNotFound => (StatusCode::NOT_FOUND, Json(Error { message: "session not found".to_string() })),
+        }
+    }
+}
+
+impl From<PhotoServiceError> for (StatusCode, Json<Error>) {
+    fn from(service_error: PhotoServiceError) -> Self {
+        log::error!("Photo Service Error: {:?}", service_error);
+        match service_error {
+            PhotoServiceError::Storage(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            PhotoServiceError::Blob(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            PhotoServiceError::Privacy(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            PhotoServiceError::InvalidImage => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid image".to_string() })),
+            PhotoServiceError::PhotoNotFound => (StatusCode::NOT_FOUND, Json(Error { message: "photo not found".to_string() })),
+        }
+    }
+}
+
+impl From<EmailServiceError> for (StatusCode, Json<Error>) {
+    fn from(service_error: EmailServiceError) -> Self {
+        log::error!("Email Service Error: {:?}", service_error);
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() }))
+    }
+}
+
+impl From<PasswordServiceError> for (StatusCode, Json<Error>) {
+    fn from(service_error: PasswordServiceError) -> Self {
+        log::error!("Password Service Error: {:?}", service_error);
+        match service_error {
+            PasswordServiceError::Storage(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            PasswordServiceError::Email(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            PasswordServiceError::Hashing => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            PasswordServiceError::InvalidPassword => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid password".to_string() })),
+            PasswordServiceError::InvalidHint => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid hint".to_string() })),
+            PasswordServiceError::InvalidEmail => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid email".to_string() })),
+            PasswordServiceError::InvalidOTP => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid otp".to_string() })),
+            PasswordServiceError::OTPNotSent => (StatusCode::BAD_REQUEST, Json(Error { message: "otp not sent".to_string() })),
+            PasswordServiceError::PasswordNotSet => (StatusCode::BAD_REQUEST, Json(Error { message: "password not set".to_string() })),
+            PasswordServiceError::NoRecoveryEmail => (StatusCode::BAD_REQUEST, Json(Error { message: "no recovery email".to_string() })),
+            PasswordServiceError::TooManyAttempts => (StatusCode::TOO_MANY_REQUESTS, Json(Error { message: "too many attempts".to_string() })),
+        }
+    }
+}
+
+impl From<ContactServiceError> for (StatusCode, Json<Error>) {
+    fn from(service_error: ContactServiceError) -> Self {
+        log::error!("Contact Service Error: {:?}", service_error);
+        match service_error {
+            ContactServiceError::Storage(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            ContactServiceError::Privacy(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            ContactServiceError::InvalidUser => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid user".to_string() })),
+            ContactServiceError::InvalidName => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid name".to_string() })),
+            ContactServiceError::ContactNotFound => (StatusCode::NOT_FOUND, Json(Error { message: "contact not found".to_string() })),
+        }
+    }
+}
+
+impl From<PrivacyServiceError> for (StatusCode, Json<Error>) {
+    fn from(service_error: PrivacyServiceError) -> Self {
+        log::error!("Privacy Service Error: {:?}", service_error);
+        match service_error {
+            PrivacyServiceError::Storage(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            PrivacyServiceError::InvalidRule => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid privacy rule".to_string() })),
+            PrivacyServiceError::InvalidUser => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid user".to_string() })),
+        }
+    }
+}
+
+impl From<PresenceServiceError> for (StatusCode, Json<Error>) {
+    fn from(service_error: PresenceServiceError) -> Self {
+        log::error!("Presence Service Error: {:?}", service_error);
+        match service_error {
+            PresenceServiceError::Storage(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            PresenceServiceError::Privacy(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            PresenceServiceError::UserNotFound => (StatusCode::NOT_FOUND, Json(Error { message: "user not found".to_string() })),
         }
     }
 }
@@ -70,8 +232,18 @@ impl From<MessageServiceError> for (StatusCode, Json<Error>) {
         log::error!("Message Service Error: {:?}", service_error);
         match service_error {
             MessageServiceError::Storage(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
+            MessageServiceError::Privacy(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Error { message: "service isn't avaible".to_string() })),
             MessageServiceError::InvalidMessage => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid message".to_string() })),
+            MessageServiceError::InvalidEntities => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid entities".to_string() })),
             MessageServiceError::InvalidChat => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid chat".to_string() })),
+            MessageServiceError::InvalidReply => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid reply".to_string() })),
+            MessageServiceError::InvalidThread => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid thread".to_string() })),
+            MessageServiceError::InvalidSchedule => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid schedule time".to_string() })),
+            MessageServiceError::InvalidTtl => (StatusCode::BAD_REQUEST, Json(Error { message: "invalid ttl".to_string() })),
+            MessageServiceError::ScheduledMessageNotFound => (StatusCode::NOT_FOUND, Json(Error { message: "scheduled message not found".to_string() })),
+            MessageServiceError::ScheduledMessageSending => (StatusCode::CONFLICT, Json(Error { message: "scheduled message is being sent".to_string() })),
+            MessageServiceError::Blocked => (StatusCode::FORBIDDEN, Json(Error { message: "you can't message this user".to_string() })),
+            MessageServiceError::PrivacyRestricted => (StatusCode::FORBIDDEN, Json(Error { message: "privacy settings of the user don't allow this".to_string() })),
         }
     }
 }
@@ -79,33 +251,151 @@ impl From<MessageServiceError> for (StatusCode, Json<Error>) {
 pub async fn try_auth_user(
     State(state): State<AppState>,
     Json(payload): Json<AuthenticateRequest>,
-) -> Result<Json<AuthenticateResponse>, (StatusCode, Json<Error>)> {
+) -> Result<Json<OtpStatus>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let email_service = ImplEmailService::new(state.storage.clone());
+    let otp_status = user_service.send_otp(&payload.email, &email_service).await?;
+    Ok(Json(otp_status))
+}
+
+pub async fn resend_otp(
+    State(state): State<AppState>,
+    Json(payload): Json<AuthenticateRequest>,
+) -> Result<Json<OtpStatus>, (StatusCode, Json<Error>)> {
     let user_service = ImplUserService::new(state.storage.clone());
-    let email_service = ImplEmailService::new();
-    let otp_hash = user_service.send_otp(&payload.email, &email_service).await?;
-    Ok(Json(AuthenticateResponse { otp_hash }))
+    let email_service = ImplEmailService::new(state.storage.clone());
+    let otp_status = user_service.resend_otp(&payload.email, &email_service).await?;
+    Ok(Json(otp_status))
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct TokenRequest {
     pub email: String,
     pub otp: String,
+    pub attempt_id: String,
+    pub device_name: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct TokenResponse {
-    pub token: String,
+    pub token: Option<String>,
+    pub refresh_token: Option<String>,
     pub has_account: bool,
+    /// Set when there's no account for the email yet. It's the bearer token for creating one.
+    pub signup_token: Option<String>,
+    pub password_required: bool,
+    pub password_token: Option<String>,
+    pub password_hint: Option<String>,
 }
 
 pub async fn get_token(
     State(state): State<AppState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(payload): Json<TokenRequest>,
 ) -> Result<Json<TokenResponse>, (StatusCode, Json<Error>)> {
     let user_service = ImplUserService::new(state.storage.clone());
-    let token = user_service.verify_otp(&payload.email, &payload.otp).await?;
-    let has_account = user_service.authenticate(&token).await?.is_some();
-    Ok(Json(TokenResponse { token, has_account }))
+    let ip = address.ip().to_string();
+    let new_session = NewSession {
+        device_name: payload.device_name.as_deref(),
+        ip: Some(&ip),
+        user_agent: headers.get(header::USER_AGENT).and_then(|user_agent| user_agent.to_str().ok()),
+    };
+    match user_service.verify_otp(&payload.email, &payload.otp, &payload.attempt_id, &new_session).await? {
+        Login::Tokens(Tokens { access_token, refresh_token }) => Ok(Json(TokenResponse {
+            token: Some(access_token),
+            refresh_token: Some(refresh_token),
+            has_account: true,
+            signup_token: None,
+            password_required: false,
+            password_token: None,
+            password_hint: None,
+        })),
+        Login::SignupRequired { signup_token } => Ok(Json(TokenResponse {
+            token: None,
+            refresh_token: None,
+            has_account: false,
+            signup_token: Some(signup_token),
+            password_required: false,
+            password_token: None,
+            password_hint: None,
+        })),
+        Login::PasswordRequired { password_token, hint } => Ok(Json(TokenResponse {
+            token: None,
+            refresh_token: None,
+            has_account: true,
+            signup_token: None,
+            password_required: true,
+            password_token: Some(password_token),
+            password_hint: hint,
+        })),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PasswordLoginRequest {
+    pub password_token: String,
+    pub password: String,
+}
+
+pub async fn verify_password(
+    State(state): State<AppState>,
+    Json(payload): Json<PasswordLoginRequest>,
+) -> Result<Json<RefreshTokenResponse>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let Tokens { access_token, refresh_token } = user_service.verify_password(&payload.password_token, &payload.password).await?;
+    Ok(Json(RefreshTokenResponse { token: access_token, refresh_token }))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PasswordRecoveryRequest {
+    pub password_token: String,
+}
+
+pub async fn send_password_recovery(
+    State(state): State<AppState>,
+    Json(payload): Json<PasswordRecoveryRequest>,
+) -> Result<StatusCode, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let email_service = ImplEmailService::new(state.storage.clone());
+    user_service.send_password_recovery(&payload.password_token, &email_service).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ConfirmPasswordRecoveryRequest {
+    pub password_token: String,
+    pub otp: String,
+}
+
+pub async fn recover_password(
+    State(state): State<AppState>,
+    Json(payload): Json<ConfirmPasswordRecoveryRequest>,
+) -> Result<Json<RefreshTokenResponse>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let Tokens { access_token, refresh_token } = user_service.recover_password(&payload.password_token, &payload.otp).await?;
+    Ok(Json(RefreshTokenResponse { token: access_token, refresh_token }))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RefreshTokenResponse {
+    pub token: String,
+    pub refresh_token: String,
+}
+
+pub async fn refresh_token(
+    State(state): State<AppState>,
+    Json(payload): Json<RefreshTokenRequest>,
+) -> Result<Json<RefreshTokenResponse>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    let Tokens { access_token, refresh_token } = user_service.refresh_tokens(&payload.refresh_token, &event_service).await?;
+    Ok(Json(RefreshTokenResponse { token: access_token, refresh_token }))
 }
 
 #[derive(Deserialize, Serialize)]
@@ -114,25 +404,430 @@ pub struct CreateUserRequest {
     pub last_name: Option<String>,
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct CreateUserResponse {
+    pub user: User,
+    pub token: String,
+    pub refresh_token: String,
+}
+
 pub async fn create_user(
     State(state): State<AppState>,
-    AuthBearer(token): AuthBearer,
+    AuthBearer(signup_token): AuthBearer,
     Json(payload): Json<CreateUserRequest>,
+) -> Result<Json<CreateUserResponse>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let (user, Tokens { access_token, refresh_token }) = user_service.create_user(&signup_token, &payload.first_name, payload.last_name.as_deref()).await?;
+    Ok(Json(CreateUserResponse { user, token: access_token, refresh_token }))
+}
+
+pub async fn get_me(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
 ) -> Result<Json<User>, (StatusCode, Json<Error>)> {
     let user_service = ImplUserService::new(state.storage.clone());
-    let user = user_service.create_user(&token, &payload.first_name, payload.last_name.as_deref()).await?;
+    let user = user_service.authenticate_with_user(&token).await?;
     Ok(Json(user))
 }
 
-pub async fn get_me(
+#[derive(Deserialize, Serialize)]
+pub struct DeleteMeRequest {
+    pub otp: String,
+    pub attempt_id: String,
+    pub delete_messages: Option<bool>,
+}
+
+pub async fn delete_me(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(payload): Json<DeleteMeRequest>,
+) -> Result<StatusCode, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let photo_service = ImplPhotoService::new(state.storage.clone(), state.blob_store.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    user_service.delete_me(&token, &payload.otp, &payload.attempt_id, payload.delete_messages.unwrap_or(false), &photo_service, &event_service).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_password_info(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PasswordInfo>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let password_service = ImplPasswordService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let password_info = password_service.get_password_info(user.id).await?;
+    Ok(Json(password_info))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SetPasswordRequest {
+    pub current_password: Option<String>,
+    pub password: String,
+    pub hint: Option<String>,
+    pub recovery_email: Option<String>,
+}
+
+pub async fn set_password(
     State(state): State<AppState>,
     AuthBearer(token): AuthBearer,
+    Json(payload): Json<SetPasswordRequest>,
+) -> Result<Json<PasswordInfo>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let password_service = ImplPasswordService::new(state.storage.clone());
+    let email_service = ImplEmailService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let password_info = password_service.set_password(
+        user.id,
+        payload.current_password.as_deref(),
+        &payload.password,
+        payload.hint.as_deref(),
+        payload.recovery_email.as_deref(),
+        &email_service,
+    ).await?;
+    Ok(Json(password_info))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ConfirmRecoveryEmailRequest {
+    pub otp: String,
+}
+
+pub async fn confirm_recovery_email(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(payload): Json<ConfirmRecoveryEmailRequest>,
+) -> Result<Json<PasswordInfo>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let password_service = ImplPasswordService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let password_info = password_service.confirm_recovery_email(user.id, &payload.otp).await?;
+    Ok(Json(password_info))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RemovePasswordRequest {
+    pub current_password: String,
+}
+
+pub async fn remove_password(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(payload): Json<RemovePasswordRequest>,
+) -> Result<StatusCode, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let password_service = ImplPasswordService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    password_service.remove_password(user.id, &payload.current_password).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_sessions(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<Session>>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let sessions = user_service.get_sessions(&token).await?;
+    Ok(Json(sessions))
+}
+
+pub async fn terminate_session(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(session_id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    user_service.terminate_session(&token, session_id, &event_service).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn terminate_other_sessions(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<StatusCode, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    user_service.terminate_other_sessions(&token, &event_service).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct EmailChangeRequest {
+    pub email: String,
+}
+
+pub async fn request_email_change(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(payload): Json<EmailChangeRequest>,
+) -> Result<Json<OtpStatus>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let email_service = ImplEmailService::new(state.storage.clone());
+    let otp_status = user_service.request_email_change(&token, &payload.email, &email_service).await?;
+    Ok(Json(otp_status))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ConfirmEmailChangeRequest {
+    pub old_otp: String,
+    pub new_otp: String,
+}
+
+pub async fn confirm_email_change(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(payload): Json<ConfirmEmailChangeRequest>,
 ) -> Result<Json<User>, (StatusCode, Json<Error>)> {
     let user_service = ImplUserService::new(state.storage.clone());
+    let email_service = ImplEmailService::new(state.storage.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    let user = user_service.confirm_email_change(&token, &payload.old_otp, &payload.new_otp, &email_service, &event_service).await?;
+    Ok(Json(user))
+}
+
+pub async fn upload_photo(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    mut multipart: Multipart,
+) -> Result<Json<Photo>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let photo_service = ImplPhotoService::new(state.storage.clone(), state.blob_store.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let invalid_upload = || (StatusCode::BAD_REQUEST, Json(Error { message: "invalid upload".to_string() }));
+    let mut data = None;
+    while let Some(field) = multipart.next_field().await.map_err(|_| invalid_upload())? {
+        if field.name() == Some("photo") {
+            data = Some(field.bytes().await.map_err(|_| invalid_upload())?.to_vec());
+        }
+    }
+    let data = data.ok_or_else(invalid_upload)?;
+    let photo = photo_service.upload_photo(&user, data, &event_service).await?;
+    Ok(Json(photo))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SetPhotoRequest {
+    pub photo_id: Option<i64>,
+}
+
+pub async fn set_photo(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(payload): Json<SetPhotoRequest>,
+) -> Result<Json<User>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let photo_service = ImplPhotoService::new(state.storage.clone(), state.blob_store.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
     let user = user_service.authenticate_with_user(&token).await?;
+    let user = photo_service.set_photo(&user, payload.photo_id, &event_service).await?;
     Ok(Json(user))
 }
 
+pub async fn delete_photo(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(id): Path<i64>,
+) -> Result<Json<User>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let photo_service = ImplPhotoService::new(state.storage.clone(), state.blob_store.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let user = photo_service.delete_photo(&user, id, &event_service).await?;
+    Ok(Json(user))
+}
+
+pub async fn get_photos(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<Photo>>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let photo_service = ImplPhotoService::new(state.storage.clone(), state.blob_store.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let photos = photo_service.get_photos(user.id, id).await?;
+    Ok(Json(photos))
+}
+
+pub async fn get_user_status(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(id): Path<i64>,
+) -> Result<Json<UserStatus>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let presence_service = ImplPresenceService::new(state.storage.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let status = presence_service.get_status(user.id, id, &event_service).await?;
+    Ok(Json(status))
+}
+
+pub async fn get_privacy_rules(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<PrivacyRule>>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let privacy_service = ImplPrivacyService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let rules = privacy_service.get_rules(user.id).await?;
+    Ok(Json(rules))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SetPrivacyRuleRequest {
+    pub value: PrivacyValue,
+    pub allow_ids: Option<Vec<i64>>,
+    pub deny_ids: Option<Vec<i64>>,
+}
+
+pub async fn set_privacy_rule(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(key): Path<PrivacyKey>,
+    Json(payload): Json<SetPrivacyRuleRequest>,
+) -> Result<Json<PrivacyRule>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let privacy_service = ImplPrivacyService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let privacy_rule = PrivacyRule {
+        key,
+        value: payload.value,
+        allow_ids: payload.allow_ids.unwrap_or_default(),
+        deny_ids: payload.deny_ids.unwrap_or_default(),
+    };
+    let privacy_rule = privacy_service.set_rule(user.id, &privacy_rule).await?;
+    Ok(Json(privacy_rule))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ContactsQuery {
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+pub async fn get_contacts(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<ContactsQuery>,
+) -> Result<Json<Vec<Contact>>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let contact_service = ImplContactService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let contacts = contact_service.get_contacts(user.id, query.offset.unwrap_or_default(), query.limit.unwrap_or(100)).await?;
+    Ok(Json(contacts))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct AddContactRequest {
+    pub user_id: i64,
+    pub first_name: String,
+    pub last_name: Option<String>,
+}
+
+pub async fn add_contact(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(payload): Json<AddContactRequest>,
+) -> Result<Json<Contact>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let contact_service = ImplContactService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let name = ContactName { first_name: payload.first_name, last_name: payload.last_name };
+    let contact = contact_service.add_contact(user.id, payload.user_id, &name).await?;
+    Ok(Json(contact))
+}
+
+pub async fn rename_contact(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(user_id): Path<i64>,
+    Json(payload): Json<ContactName>,
+) -> Result<Json<Contact>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let contact_service = ImplContactService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let contact = contact_service.rename_contact(user.id, user_id, &payload).await?;
+    Ok(Json(contact))
+}
+
+pub async fn delete_contact(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(user_id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let contact_service = ImplContactService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    contact_service.delete_contact(user.id, user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BlockedUsersQuery {
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+pub async fn get_blocked_users(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<BlockedUsersQuery>,
+) -> Result<Json<Vec<BlockedUser>>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let privacy_service = ImplPrivacyService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let blocked_users = privacy_service.get_blocked_users(user.id, query.offset.unwrap_or_default(), query.limit.unwrap_or(50)).await?;
+    Ok(Json(blocked_users))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BlockUserRequest {
+    pub user_id: i64,
+}
+
+pub async fn block_user(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(payload): Json<BlockUserRequest>,
+) -> Result<StatusCode, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let privacy_service = ImplPrivacyService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    privacy_service.block_user(user.id, payload.user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn unblock_user(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(user_id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let privacy_service = ImplPrivacyService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    privacy_service.unblock_user(user.id, user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PhotoFileQuery {
+    pub size: Option<PhotoSize>,
+}
+
+pub async fn get_photo_file(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(id): Path<i64>,
+    Query(query): Query<PhotoFileQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let photo_service = ImplPhotoService::new(state.storage.clone(), state.blob_store.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let data = photo_service.get_photo_file(user.id, id, query.size.unwrap_or(PhotoSize::Big)).await?;
+    Ok(([(header::CONTENT_TYPE, "image/jpeg")], data))
+}
+
 
 #[derive(Deserialize, Serialize)]
 pub struct PatchMeRequest {
@@ -152,37 +847,341 @@ pub async fn patch_me(
 #[derive(Deserialize, Serialize)]
 pub struct SendMessageRequest {
     pub text: String,
+    pub entities: Option<Vec<MessageEntity>>,
+    pub parse_mode: Option<ParseMode>,
     pub chat_id: Option<i64>,
     pub username: Option<String>,
+    pub reply_to_id: Option<i64>,
+    pub thread_id: Option<i64>,
+    pub ttl: Option<u32>,
+    pub schedule_at: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum SendMessageResponse {
+    Message(Message),
+    Scheduled(ScheduledMessage),
 }
 
 pub async fn send_message(
     State(state): State<AppState>,
     AuthBearer(token): AuthBearer,
     Json(payload): Json<SendMessageRequest>,
-) -> Result<Json<Message>, (StatusCode, Json<Error>)> {
+) -> Result<Json<SendMessageResponse>, (StatusCode, Json<Error>)> {
     let user_service = ImplUserService::new(state.storage.clone());
     let message_service = ImplMessageService::new(state.storage.clone());
     let event_service = EventService::new(state.listener_pool.clone());
     let user = user_service.authenticate_with_user(&token).await?;
+    let message_request = MessageRequest {
+        text: payload.text,
+        entities: payload.entities.unwrap_or_default(),
+        parse_mode: payload.parse_mode,
+        reply_to_id: payload.reply_to_id,
+        thread_id: payload.thread_id,
+        ttl: payload.ttl,
+    };
+    if let Some(schedule_at) = payload.schedule_at {
+        let scheduled_message = message_service.schedule_message(
+            user.id,
+            payload.chat_id,
+            payload.username.as_deref(),
+            &message_request,
+            schedule_at,
+            &event_service,
+        ).await?;
+        return Ok(Json(SendMessageResponse::Scheduled(scheduled_message)));
+    }
     let message = message_service.auto_send_message(
         user.id,
         payload.chat_id,
         payload.username.as_deref(),
-        &MessageRequest { text: payload.text },
+        &message_request,
         &event_service,
     ).await?;
+    let preview_service = PreviewService::new(state.storage.clone(), state.link_preview_fetcher.clone());
+    preview_service.spawn_attach_preview(message.clone(), event_service);
+    Ok(Json(SendMessageResponse::Message(message)))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ForwardMessageRequest {
+    pub message_id: i64,
+    pub chat_id: Option<i64>,
+}
+
+pub async fn forward_message(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(payload): Json<ForwardMessageRequest>,
+) -> Result<Json<Message>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let message = message_service.forward_message(user.id, payload.message_id, payload.chat_id, &event_service).await?;
+    Ok(Json(message))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MarkViewedRequest {
+    pub message_ids: Vec<i64>,
+}
+
+pub async fn mark_viewed(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(payload): Json<MarkViewedRequest>,
+) -> Result<Json<Vec<MessageViews>>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let views = message_service.mark_viewed(user.id, &payload.message_ids).await?;
+    Ok(Json(views))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ThreadQuery {
+    pub offset_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+pub async fn get_thread(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(id): Path<i64>,
+    Query(query): Query<ThreadQuery>,
+) -> Result<Json<Thread>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let thread = message_service.get_thread(user.id, id, query.offset_id.unwrap_or_default(), query.limit.unwrap_or(50)).await?;
+    Ok(Json(thread))
+}
+
+pub async fn get_scheduled_messages(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(chat_id): Path<i64>,
+) -> Result<Json<Vec<ScheduledMessage>>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let scheduled_messages = message_service.get_scheduled_messages(user.id, chat_id).await?;
+    Ok(Json(scheduled_messages))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct EditScheduledMessageRequest {
+    pub text: Option<String>,
+    pub entities: Option<Vec<MessageEntity>>,
+    pub parse_mode: Option<ParseMode>,
+    pub reply_to_id: Option<i64>,
+    pub thread_id: Option<i64>,
+    pub ttl: Option<u32>,
+    pub schedule_at: Option<usize>,
+}
+
+pub async fn edit_scheduled_message(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(id): Path<i64>,
+    Json(payload): Json<EditScheduledMessageRequest>,
+) -> Result<Json<ScheduledMessage>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let message_request = payload.text.map(|text| MessageRequest {
+        text,
+        entities: payload.entities.unwrap_or_default(),
+        parse_mode: payload.parse_mode,
+        reply_to_id: payload.reply_to_id,
+        thread_id: payload.thread_id,
+        ttl: payload.ttl,
+    });
+    let scheduled_message = message_service.edit_scheduled_message(user.id, id, message_request.as_ref(), payload.schedule_at).await?;
+    Ok(Json(scheduled_message))
+}
+
+pub async fn cancel_scheduled_message(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    message_service.cancel_scheduled_message(user.id, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn send_scheduled_message(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(id): Path<i64>,
+) -> Result<Json<Message>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let message = message_service.send_scheduled_message(user.id, id, &event_service).await?;
+    let preview_service = PreviewService::new(state.storage.clone(), state.link_preview_fetcher.clone());
+    preview_service.spawn_attach_preview(message.clone(), event_service);
+    Ok(Json(message))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SetChatTtlRequest {
+    pub ttl: Option<u32>,
+}
+
+pub async fn set_chat_ttl(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(chat_id): Path<i64>,
+    Json(payload): Json<SetChatTtlRequest>,
+) -> Result<Json<Message>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let message = message_service.set_chat_ttl(user.id, chat_id, payload.ttl, &event_service).await?;
     Ok(Json(message))
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct SaveDraftRequest {
+    pub text: String,
+    pub entities: Option<Vec<MessageEntity>>,
+    pub reply_to_id: Option<i64>,
+}
+
+pub async fn save_draft(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(chat_id): Path<i64>,
+    Json(payload): Json<SaveDraftRequest>,
+) -> Result<Json<Option<Draft>>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let draft = message_service.save_draft(
+        user.id,
+        chat_id,
+        &payload.text,
+        &payload.entities.unwrap_or_default(),
+        payload.reply_to_id,
+        &event_service,
+    ).await?;
+    Ok(Json(draft))
+}
+
+pub async fn clear_draft(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(chat_id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let event_service = EventService::new(state.listener_pool.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    message_service.clear_draft(user.id, chat_id, &event_service).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DialogsQuery {
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+pub async fn get_dialogs(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<DialogsQuery>,
+) -> Result<Json<Vec<Dialog>>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let dialogs = message_service.get_dialogs(user.id, query.offset.unwrap_or_default(), query.limit.unwrap_or(50)).await?;
+    Ok(Json(dialogs))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MentionsResponse {
+    pub count: i64,
+    pub messages: Vec<Message>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MentionsQuery {
+    pub offset_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+pub async fn get_mentions(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<MentionsQuery>,
+) -> Result<Json<MentionsResponse>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let (count, messages) = message_service.get_unread_mentions(user.id, query.offset_id.unwrap_or_default(), query.limit.unwrap_or(50)).await?;
+    Ok(Json(MentionsResponse { count, messages }))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ReadMentionsRequest {
+    pub message_ids: Option<Vec<i64>>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ReadMentionsResponse {
+    pub count: i64,
+}
+
+pub async fn read_mentions(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(payload): Json<ReadMentionsRequest>,
+) -> Result<Json<ReadMentionsResponse>, (StatusCode, Json<Error>)> {
+    let user_service = ImplUserService::new(state.storage.clone());
+    let message_service = ImplMessageService::new(state.storage.clone());
+    let user = user_service.authenticate_with_user(&token).await?;
+    let count = message_service.read_mentions(user.id, payload.message_ids.as_deref()).await?;
+    Ok(Json(ReadMentionsResponse { count }))
+}
+
 pub async fn get_events(
     State(state): State<AppState>,
     AuthBearer(token): AuthBearer,
 ) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, Json<Error>)> {
     let user_service = ImplUserService::new(state.storage.clone());
-    let user = user_service.authenticate_with_user(&token).await?;
+    let (user, session_id) = user_service.authenticate_session(&token).await?;
     let event_service = EventService::new(state.listener_pool.clone());
-    let stream = event_service.get_user_stream(user.id).await;
+    let stream = event_service.get_user_stream(user.id, session_id).await;
     let sse = Sse::new(stream.map(|event| Ok(Event::default().json_data(event).unwrap())));
     Ok(sse)
 }
+
+#[derive(Deserialize, Serialize)]
+pub struct EmailDeliveriesQuery {
+    pub email: String,
+}
+
+/// Lets support see what happened to the emails sent to an address. Needs `SUPPORT_TOKEN`.
+pub async fn get_email_deliveries(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<EmailDeliveriesQuery>,
+) -> Result<Json<Vec<EmailDelivery>>, (StatusCode, Json<Error>)> {
+    let support_token = std::env::var("SUPPORT_TOKEN").unwrap_or_default();
+    if support_token.is_empty() || !random::constant_time_eq(&token, &support_token) {
+        return Err((StatusCode::UNAUTHORIZED, Json(Error { message: "invalid authentication".to_string() })));
+    }
+    let email_service = ImplEmailService::new(state.storage.clone());
+    let deliveries = email_service.get_deliveries(&query.email).await?;
+    Ok(Json(deliveries))
+}
@@ -1,17 +1,115 @@
 use std::time::SystemTime;
 
 use redis::AsyncCommands;
+use sha2::Digest;
 use types::{DbItem, DbOTP};
 
-use crate::models::{self, User};
+use crate::{models::{self, LinkPreview, MessageAction, MessageEntity, User}, random};
 
 mod types;
 
+/// How long, in seconds, Redis remembers who viewed a message since its last view.
+const VIEWERS_TTL: u64 = 30 * 86400;
+
+/// Where a session was started from, as far as the client tells.
+pub struct NewSession<'a> {
+    pub device_name: Option<&'a str>,
+    pub ip: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
+/// A pending change of the account email. Both addresses get their own code.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct EmailChange {
+    pub new_email: String,
+    pub old_otp: HashedOtp,
+    pub new_otp: HashedOtp,
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+/// A one-time code as it's kept: only its salted, keyed hash (see `random::hash_otp`).
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct HashedOtp {
+    pub salt: String,
+    pub otp_hash: String,
+}
+
+impl HashedOtp {
+    pub fn new(otp: &str) -> Self {
+        let salt = random::random_word(16);
+        Self { otp_hash: random::hash_otp(&salt, otp), salt }
+    }
+
+    pub fn matches(&self, otp: &str) -> bool {
+        random::constant_time_eq(&random::hash_otp(&self.salt, otp), &self.otp_hash)
+    }
+}
+
+/// A recovery email that waits for the code sent to it before it's used.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RecoveryEmailChange {
+    pub email: String,
+    pub otp: HashedOtp,
+}
+
+/// A user as someone else sees them, with what the privacy rules need to know about the two of them.
+#[derive(Debug, Clone)]
+pub struct ViewedUser {
+    pub user: models::User,
+    /// The name the viewer saved the user under.
+    pub contact_name: Option<models::ContactName>,
+    /// Whether the user has the viewer in their contacts.
+    pub has_viewer_as_contact: bool,
+    pub has_blocked_viewer: bool,
+    /// Only the rules the user has set, without defaults.
+    pub privacy_rules: Vec<models::PrivacyRule>,
+}
+
+/// A dialog before the privacy rules of the partner are applied.
+#[derive(Debug, Clone)]
+pub struct ViewedDialog {
+    pub chat: ViewedUser,
+    pub last_message: Option<models::Message>,
+    pub draft: Option<models::Draft>,
+    pub unread_mentions_count: i64,
+}
+
+/// What `delete_account` removed that others have to hear about or clean up after the commit.
+#[derive(Debug, Clone)]
+pub struct DeletedAccount {
+    pub messages: Vec<models::DeletedMessage>,
+    pub session_ids: Vec<i64>,
+    /// The photo rows are gone, their files are left to the caller.
+    pub photo_ids: Vec<i64>,
+}
+
+/// A login that passed the email OTP and waits for the two-step verification password,
+/// or for the profile of a new account.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PendingLogin {
+    pub email: String,
+    pub device_name: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
 pub struct Storage {
     pool: sqlx::Pool<sqlx::Postgres>,
     redis: redis::Client,
 }
 
+pub struct NewMessage<'a> {
+    pub from_id: i64,
+    pub chat_id: i64,
+    pub text: &'a str,
+    pub entities: &'a [MessageEntity],
+    pub reply_to_id: Option<i64>,
+    pub forward_from_id: Option<i64>,
+    pub thread_id: Option<i64>,
+    pub expires_at: Option<usize>,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum StorageError {
     Internal,
@@ -29,6 +127,266 @@ impl From<redis::RedisError> for StorageError {
     }
 }
 
+impl From<serde_json::Error> for StorageError {
+    fn from(_: serde_json::Error) -> Self {
+        StorageError::Internal
+    }
+}
+
+fn user_from_db(item_id: i64, db_user: types::DbUser) -> models::User {
+    models::User {
+        id: item_id,
+        email: Some(db_user.email),
+        username: db_user.username,
+        first_name: db_user.first_name,
+        last_name: db_user.last_name,
+        photo_id: db_user.photo_id,
+        bio: db_user.bio,
+        birthday: db_user.birthday.map(|birthday| birthday.format("%Y-%m-%d").to_string()),
+        website: db_user.website,
+        status_text: db_user.status_text,
+        status_emoji: db_user.status_emoji,
+        is_contact: false,
+        is_mutual_contact: false,
+        is_deleted: false,
+        created_at: db_user.created_at.and_utc().timestamp() as usize,
+    }
+}
+
+/// Returns the viewed user with the time it was added to the list.
+fn listed_user_from_db(db_listed_user: types::DbListedUser) -> (ViewedUser, usize) {
+    let db_user = types::DbUser {
+        id: db_listed_user.id,
+        email: db_listed_user.email,
+        username: db_listed_user.username,
+        first_name: db_listed_user.first_name,
+        last_name: db_listed_user.last_name,
+        created_at: db_listed_user.created_at,
+        photo_id: db_listed_user.photo_id,
+        bio: db_listed_user.bio,
+        birthday: db_listed_user.birthday,
+        website: db_listed_user.website,
+        status_text: db_listed_user.status_text,
+        status_emoji: db_listed_user.status_emoji,
+        last_seen_at: db_listed_user.last_seen_at,
+    };
+    let contact_name = db_listed_user.contact_first_name
+        .map(|first_name| models::ContactName { first_name, last_name: db_listed_user.contact_last_name });
+    let privacy_rules: Vec<serde_json::Value> = serde_json::from_value(db_listed_user.privacy_rules).unwrap_or_default();
+    let viewed_user = ViewedUser {
+        user: user_from_db(db_listed_user.item_id, db_user),
+        contact_name,
+        has_viewer_as_contact: db_listed_user.has_viewer_as_contact,
+        has_blocked_viewer: db_listed_user.has_blocked_viewer,
+        privacy_rules: privacy_rules.into_iter().filter_map(|privacy_rule| serde_json::from_value(privacy_rule).ok()).collect(),
+    };
+    (viewed_user, db_listed_user.listed_at.and_utc().timestamp() as usize)
+}
+
+fn dialog_from_db(db_dialog: types::DbDialog) -> ViewedDialog {
+    let user = match (db_dialog.id, db_dialog.email, db_dialog.first_name, db_dialog.created_at) {
+        (Some(id), Some(email), Some(first_name), Some(created_at)) => user_from_db(db_dialog.item_id, types::DbUser {
+            id,
+            email,
+            username: db_dialog.username,
+            first_name,
+            last_name: db_dialog.last_name,
+            created_at,
+            photo_id: db_dialog.photo_id,
+            bio: db_dialog.bio,
+            birthday: db_dialog.birthday,
+            website: db_dialog.website,
+            status_text: db_dialog.status_text,
+            status_emoji: db_dialog.status_emoji,
+            last_seen_at: db_dialog.last_seen_at,
+        }),
+        _ => deleted_user(db_dialog.item_id),
+    };
+    let contact_name = db_dialog.contact_first_name
+        .map(|first_name| models::ContactName { first_name, last_name: db_dialog.contact_last_name });
+    let privacy_rules: Vec<serde_json::Value> = serde_json::from_value(db_dialog.privacy_rules).unwrap_or_default();
+    let chat = ViewedUser {
+        user,
+        contact_name,
+        has_viewer_as_contact: db_dialog.has_viewer_as_contact,
+        has_blocked_viewer: db_dialog.has_blocked_viewer,
+        privacy_rules: privacy_rules.into_iter().filter_map(|privacy_rule| serde_json::from_value(privacy_rule).ok()).collect(),
+    };
+    let last_message = match (
+        db_dialog.message_item_id,
+        db_dialog.message_id,
+        db_dialog.message_from_id,
+        db_dialog.message_chat_id,
+        db_dialog.message_created_at,
+    ) {
+        (Some(item_id), Some(id), Some(from_id), Some(chat_id), Some(created_at)) => Some(message_from_db(item_id, types::DbMessage {
+            id,
+            from_id,
+            chat_id,
+            text: db_dialog.message_text,
+            created_at,
+            entities: db_dialog.message_entities.unwrap_or_default(),
+            link_preview: db_dialog.message_link_preview,
+            action: db_dialog.message_action,
+            expires_at: db_dialog.message_expires_at,
+            reply_to_id: db_dialog.message_reply_to_id,
+            forward_from_id: db_dialog.message_forward_from_id,
+            thread_id: db_dialog.message_thread_id,
+            reply_count: db_dialog.message_reply_count.unwrap_or_default(),
+            recent_repliers: db_dialog.message_recent_repliers.unwrap_or_default(),
+            views: db_dialog.message_views.unwrap_or_default(),
+        })),
+        _ => None,
+    };
+    let draft = match (db_dialog.draft_text, db_dialog.draft_updated_at) {
+        (Some(text), Some(updated_at)) => Some(models::Draft {
+            chat_id: db_dialog.item_id,
+            text,
+            entities: db_dialog.draft_entities.and_then(|entities| serde_json::from_value(entities).ok()).unwrap_or_default(),
+            reply_to_id: db_dialog.draft_reply_to_id,
+            updated_at: updated_at.and_utc().timestamp() as usize,
+        }),
+        _ => None,
+    };
+    ViewedDialog { chat, last_message, draft, unread_mentions_count: db_dialog.unread_mentions_count }
+}
+
+/// What is left of a deleted account: the item stays, so messages keep pointing at it.
+fn deleted_user(item_id: i64) -> models::User {
+    models::User {
+        id: item_id,
+        email: None,
+        username: None,
+        first_name: "Deleted Account".to_string(),
+        last_name: None,
+        photo_id: None,
+        bio: None,
+        birthday: None,
+        website: None,
+        status_text: None,
+        status_emoji: None,
+        is_contact: false,
+        is_mutual_contact: false,
+        is_deleted: true,
+        created_at: 0,
+    }
+}
+
+fn message_from_db(item_id: i64, db_message: types::DbMessage) -> models::Message {
+    models::Message {
+        id: item_id,
+        from_id: db_message.from_id,
+        chat_id: db_message.chat_id,
+        text: db_message.text,
+        entities: serde_json::from_value(db_message.entities).unwrap_or_default(),
+        link_preview: db_message.link_preview.and_then(|link_preview| serde_json::from_value(link_preview).ok()),
+        action: db_message.action.and_then(|action| serde_json::from_value(action).ok()),
+        reply_to_id: db_message.reply_to_id,
+        forward_from_id: db_message.forward_from_id,
+        thread_id: db_message.thread_id,
+        reply_count: db_message.reply_count,
+        recent_repliers: db_message.recent_repliers,
+        views: db_message.views,
+        expires_at: db_message.expires_at.map(|expires_at| expires_at.and_utc().timestamp() as usize),
+        created_at: db_message.created_at.and_utc().timestamp() as usize,
+    }
+}
+
+fn listed_message_from_db(db_listed_message: types::DbListedMessage) -> models::Message {
+    message_from_db(db_listed_message.item_id, types::DbMessage {
+        id: db_listed_message.id,
+        from_id: db_listed_message.from_id,
+        chat_id: db_listed_message.chat_id,
+        text: db_listed_message.text,
+        created_at: db_listed_message.created_at,
+        entities: db_listed_message.entities,
+        link_preview: db_listed_message.link_preview,
+        action: db_listed_message.action,
+        expires_at: db_listed_message.expires_at,
+        reply_to_id: db_listed_message.reply_to_id,
+        forward_from_id: db_listed_message.forward_from_id,
+        thread_id: db_listed_message.thread_id,
+        reply_count: db_listed_message.reply_count,
+        recent_repliers: db_listed_message.recent_repliers,
+        views: db_listed_message.views,
+    })
+}
+
+fn scheduled_message_from_db(db_scheduled_message: types::DbScheduledMessage) -> models::ScheduledMessage {
+    models::ScheduledMessage {
+        id: db_scheduled_message.id,
+        from_id: db_scheduled_message.from_id,
+        chat_id: db_scheduled_message.chat_id,
+        text: db_scheduled_message.text,
+        entities: serde_json::from_value(db_scheduled_message.entities).unwrap_or_default(),
+        reply_to_id: db_scheduled_message.reply_to_id,
+        thread_id: db_scheduled_message.thread_id,
+        ttl: db_scheduled_message.ttl.map(|ttl| ttl as u32),
+        schedule_at: db_scheduled_message.schedule_at.and_utc().timestamp() as usize,
+        error: (db_scheduled_message.status == "failed").then(|| db_scheduled_message.last_error.unwrap_or_default()),
+        is_sending: db_scheduled_message.locked_until.is_some_and(|locked_until| locked_until > chrono::Utc::now().naive_utc()),
+        created_at: db_scheduled_message.created_at.and_utc().timestamp() as usize,
+    }
+}
+
+fn photo_from_db(db_photo: types::DbPhoto) -> models::Photo {
+    models::Photo {
+        id: db_photo.id,
+        user_id: db_photo.user_id,
+        created_at: db_photo.created_at.and_utc().timestamp() as usize,
+    }
+}
+
+fn session_from_db(db_session: types::DbSession) -> models::Session {
+    models::Session {
+        id: db_session.id,
+        device_name: db_session.device_name,
+        ip: db_session.ip,
+        user_agent: db_session.user_agent,
+        created_at: db_session.created_at.and_utc().timestamp() as usize,
+        last_active_at: db_session.last_active_at.and_utc().timestamp() as usize,
+        is_current: false,
+    }
+}
+
+fn email_delivery_from_db(db_outbox_email: types::DbOutboxEmail) -> Option<models::EmailDelivery> {
+    Some(models::EmailDelivery {
+        id: db_outbox_email.id,
+        recipient: db_outbox_email.recipient,
+        subject: db_outbox_email.subject,
+        status: serde_json::from_value(serde_json::Value::String(db_outbox_email.status)).ok()?,
+        attempts: db_outbox_email.attempts,
+        last_error: db_outbox_email.last_error,
+        next_attempt_at: db_outbox_email.next_attempt_at.and_utc().timestamp() as usize,
+        created_at: db_outbox_email.created_at.and_utc().timestamp() as usize,
+        sent_at: db_outbox_email.sent_at.map(|sent_at| sent_at.and_utc().timestamp() as usize),
+        expires_at: db_outbox_email.expires_at.map(|expires_at| expires_at.and_utc().timestamp() as usize),
+    })
+}
+
+fn draft_from_db(db_draft: types::DbDraft) -> models::Draft {
+    models::Draft {
+        chat_id: db_draft.chat_id,
+        text: db_draft.text,
+        entities: serde_json::from_value(db_draft.entities).unwrap_or_default(),
+        reply_to_id: db_draft.reply_to_id,
+        updated_at: db_draft.updated_at.and_utc().timestamp() as usize,
+    }
+}
+
+fn privacy_rule_from_db(db_privacy_rule: types::DbPrivacyRule) -> Option<models::PrivacyRule> {
+    Some(models::PrivacyRule {
+        key: serde_json::from_value(serde_json::Value::String(db_privacy_rule.key)).ok()?,
+        value: serde_json::from_value(serde_json::Value::String(db_privacy_rule.value)).ok()?,
+        allow_ids: db_privacy_rule.allow_ids,
+        deny_ids: db_privacy_rule.deny_ids,
+    })
+}
+
+fn timestamp_to_db(timestamp: usize) -> chrono::NaiveDateTime {
+    chrono::DateTime::from_timestamp(timestamp as i64, 0).unwrap_or_default().naive_utc()
+}
+
 impl Storage {
     pub async fn new() -> Self {
         let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
@@ -89,40 +447,49 @@ impl Storage {
             .fetch_one(&self.pool)
             .await?;
         let item = self.create_item(&types::Item::User(query.clone())).await?;
-        let user = models::User {
-            id: item.id,
-            email: query.email,
-            username: query.username,
-            first_name: query.first_name,
-            last_name: query.last_name,
-            created_at: query.created_at.and_utc().timestamp() as usize,
-        };
+        let user = user_from_db(item.id, query);
         Ok(user)
     }
 
-    pub async fn create_message(&self, from_id: i64, chat_id: i64, text: &str) -> Result<models::Message, StorageError> {
+    pub async fn create_message(&self, new_message: &NewMessage<'_>) -> Result<models::Message, StorageError> {
+        let db_message = sqlx::query_as!(
+                types::DbMessage,
+                r#"
+                INSERT INTO public.messages (from_id, chat_id, text, entities, reply_to_id, forward_from_id, thread_id, expires_at)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                    RETURNING *
+                "#,
+                new_message.from_id,
+                new_message.chat_id,
+                new_message.text,
+                serde_json::to_value(new_message.entities)?,
+                new_message.reply_to_id,
+                new_message.forward_from_id,
+                new_message.thread_id,
+                new_message.expires_at.map(timestamp_to_db)
+            )
+            .fetch_one(&self.pool)
+            .await?;
+        let item = self.create_item(&types::Item::Message(db_message.clone())).await?;
+        Ok(message_from_db(item.id, db_message))
+    }
+
+    pub async fn create_service_message(&self, from_id: i64, chat_id: i64, action: &MessageAction) -> Result<models::Message, StorageError> {
         let db_message = sqlx::query_as!(
                 types::DbMessage,
                 r#"
-                INSERT INTO public.messages (from_id, chat_id, text)
+                INSERT INTO public.messages (from_id, chat_id, action)
                     VALUES ($1, $2, $3)
                     RETURNING *
                 "#,
                 from_id,
                 chat_id,
-                text
+                serde_json::to_value(action)?
             )
             .fetch_one(&self.pool)
             .await?;
         let item = self.create_item(&types::Item::Message(db_message.clone())).await?;
-        let message = models::Message {
-            id: item.id,
-            from_id: db_message.from_id,
-            chat_id: db_message.chat_id,
-            text: db_message.text,
-            created_at: db_message.created_at.and_utc().timestamp() as usize,
-        };
-        Ok(message)
+        Ok(message_from_db(item.id, db_message))
     }
 
     pub async fn get_user(&self, item_id: i64) -> Result<Option<models::User>, StorageError> {
@@ -131,14 +498,7 @@ impl Storage {
             return Ok(None);
         }
         let db_user = db_user.unwrap();
-        let user = models::User {
-            id: item_id,
-            email: db_user.email,
-            username: db_user.username,
-            first_name: db_user.first_name,
-            last_name: db_user.last_name,
-            created_at: db_user.created_at.and_utc().timestamp() as usize,
-        };
+        let user = user_from_db(item_id, db_user);
         Ok(Some(user))
     }
 
@@ -173,20 +533,30 @@ impl Storage {
         if let Some(db_user) = query {
             let item = self.get_item_of_user(db_user.id).await?;
             if let Some(item) = item {
-                let user = models::User {
-                    id: item.id,
-                    email: db_user.email,
-                    username: db_user.username,
-                    first_name: db_user.first_name,
-                    last_name: db_user.last_name,
-                    created_at: db_user.created_at.and_utc().timestamp() as usize,
-                };
+                let user = user_from_db(item.id, db_user);
                 return Ok(Some(user));
             }
         }
         Ok(None)
     }
 
+    pub async fn get_messages(&self, item_ids: &[i64]) -> Result<Vec<models::Message>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbListedMessage,
+                r#"
+                SELECT items.id AS "item_id!", messages.*
+                    FROM public.items
+                    JOIN public.messages ON items.message_id = messages.id
+                    WHERE items.id = ANY($1)
+                    ORDER BY items.id
+                "#,
+                item_ids
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().map(listed_message_from_db).collect())
+    }
+
     pub async fn get_message(&self, item_id: i64) -> Result<Option<models::Message>, StorageError> {
         let query = sqlx::query_as!(
                 types::DbMessage,
@@ -200,45 +570,64 @@ impl Storage {
             )
             .fetch_optional(&self.pool)
             .await?;
-        Ok(query.map(|db_message| {
-            models::Message {
-                id: item_id,
-                from_id: db_message.from_id,
-                chat_id: db_message.chat_id,
-                text: db_message.text,
-                created_at: db_message.created_at.and_utc().timestamp() as usize,
-            }
-        }))
+        Ok(query.map(|db_message| message_from_db(item_id, db_message)))
     }
 
-    pub async fn store_otp(&self, email: &str, otp: &str) -> Result<(), StorageError> {
+    pub async fn store_otp(&self, email: &str, otp_hash: &str, salt: &str, attempt_hash: &str, lifetime: u64) -> Result<(), StorageError> {
         let mut con = self.redis.get_multiplexed_async_connection().await?;
         con.set_ex::<_, _, ()>(
             format!("otp:{}", email),
             DbOTP {
-                otp: otp.to_string(),
+                otp_hash: otp_hash.to_string(),
+                salt: salt.to_string(),
+                attempt_hash: attempt_hash.to_string(),
                 created_at: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
             }.to_string(),
-            300).await?;
+            lifetime).await?;
         Ok(())
     }
 
-    pub async fn get_otp(&self, email: &str) -> Result<Option<(String, SystemTime)>, StorageError> {
+    pub async fn get_otp(&self, email: &str) -> Result<Option<DbOTP>, StorageError> {
         let mut con = self.redis.get_multiplexed_async_connection().await?;
         let key = format!("otp:{}", email);
         let db_otp: Option<DbOTP> = con.get(&key).await?;
-        if let Some(db_otp) = db_otp {
-            let created_at = SystemTime::UNIX_EPOCH + std::time::Duration::new(db_otp.created_at, 0);
-            Ok(Some((db_otp.otp, created_at)))
-        } else {
-            Ok(None)
-        }
+        Ok(db_otp)
     }
 
+    /// Also forgets the wrong guesses made against the code.
     pub async fn delete_otp(&self, email: &str) -> Result<(), StorageError> {
         let mut con = self.redis.get_multiplexed_async_connection().await?;
-        let key = format!("otp:{}", email);
-        let _:() = con.del(key).await?;
+        let _:() = con.del(&[format!("otp:{}", email), format!("otp_attempts:{}", email)]).await?;
+        Ok(())
+    }
+
+    /// Counts a guess against the current code and returns how many were made.
+    pub async fn add_otp_attempt(&self, email: &str) -> Result<i64, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let key = format!("otp_attempts:{}", email);
+        let attempts: i64 = con.incr(&key, 1).await?;
+        if attempts == 1 {
+            let _: () = con.expire(&key, 300).await?;
+        }
+        Ok(attempts)
+    }
+
+    /// Counts an OTP check for a lockout key (an email or an IP) within the window.
+    /// It's counted before the code is compared, so parallel guesses can't slip past the limit.
+    pub async fn add_otp_failure(&self, key: &str, window: i64) -> Result<i64, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let key = format!("otp_failures:{}", key);
+        let failures: i64 = con.incr(&key, 1).await?;
+        if failures == 1 {
+            let _: () = con.expire(&key, window).await?;
+        }
+        Ok(failures)
+    }
+
+    /// Takes back a check counted by `add_otp_failure` once it turned out right.
+    pub async fn remove_otp_failure(&self, key: &str) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let _: i64 = con.decr(format!("otp_failures:{}", key), 1).await?;
         Ok(())
     }
 
@@ -268,27 +657,25 @@ impl Storage {
                 types::DbUser,
                 r#"
                 UPDATE public.users
-                    SET first_name = $1, last_name = $2, username = $3
-                    WHERE id = $4
+                    SET first_name = $1, last_name = $2, username = $3, bio = $4, birthday = $5, website = $6, status_text = $7, status_emoji = $8
+                    WHERE id = $9
                     RETURNING *
                 "#,
                 user.first_name,
                 user.last_name,
                 user.username,
+                user.bio,
+                user.birthday.as_deref().and_then(|birthday| chrono::NaiveDate::parse_from_str(birthday, "%Y-%m-%d").ok()),
+                user.website,
+                user.status_text,
+                user.status_emoji,
                 db_user.id
             )
             .fetch_one(&self.pool)
             .await?;
         let item = self.get_item_of_user(query.id).await?;
         if let Some(item) = item {
-            let updated_user = models::User {
-                id: item.id,
-                email: query.email,
-                username: query.username,
-                first_name: query.first_name,
-                last_name: query.last_name,
-                created_at: query.created_at.and_utc().timestamp() as usize,
-            };
+            let updated_user = user_from_db(item.id, query);
             return Ok(updated_user)
         }
         Err(StorageError::Internal)
@@ -315,14 +702,7 @@ impl Storage {
             return Ok(None);
         }
         let item = item.unwrap();
-        let user = models::User {
-            id: item.id,
-            email: db_user.email,
-            username: db_user.username,
-            first_name: db_user.first_name,
-            last_name: db_user.last_name,
-            created_at: db_user.created_at.and_utc().timestamp() as usize,
-        };
+        let user = user_from_db(item.id, db_user);
         Ok(Some(user))
     }
 
@@ -359,5 +739,1605 @@ impl Storage {
             .await?;
         Ok(())
     }
-}
 
+    pub async fn add_mention(&self, user_id: i64, message_id: i64) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"
+                INSERT INTO public.mentions (user_id, message_id)
+                    VALUES ($1, $2)
+                    ON CONFLICT (user_id, message_id) DO NOTHING
+                "#,
+                user_id,
+                message_id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Newest first. Pages go on from the mention of the `offset_id` message, 0 starts from the newest.
+    pub async fn get_unread_mentions(&self, user_id: i64, offset_id: i64, limit: i64) -> Result<Vec<models::Message>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbListedMessage,
+                r#"
+                SELECT items.id AS "item_id!", messages.*
+                    FROM public.mentions
+                    JOIN public.items ON items.id = mentions.message_id
+                    JOIN public.messages ON messages.id = items.message_id
+                    WHERE mentions.user_id = $1 AND NOT mentions.read AND ($2::bigint = 0 OR mentions.message_id < $2)
+                    ORDER BY mentions.message_id DESC
+                    LIMIT $3
+                "#,
+                user_id,
+                offset_id,
+                limit
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().map(listed_message_from_db).collect())
+    }
+
+    pub async fn count_unread_mentions(&self, user_id: i64) -> Result<i64, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                SELECT COUNT(*)
+                    FROM public.mentions
+                    WHERE user_id = $1 AND NOT read
+                "#,
+                user_id
+            )
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(query.count.unwrap_or(0))
+    }
+
+    pub async fn read_mentions(&self, user_id: i64, message_ids: Option<&[i64]>) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"
+                UPDATE public.mentions
+                    SET read = true
+                    WHERE user_id = $1 AND ($2::bigint[] IS NULL OR message_id = ANY($2))
+                "#,
+                user_id,
+                message_ids
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns `Some(None)` when the url is known to have no preview.
+    pub async fn get_cached_preview(&self, url: &str) -> Result<Option<Option<LinkPreview>>, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let key = format!("preview:{}", hex::encode(sha2::Sha256::digest(url.as_bytes())));
+        let cached: Option<String> = con.get(&key).await?;
+        match cached {
+            Some(cached) => Ok(Some(serde_json::from_str(&cached)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn cache_preview(&self, url: &str, preview: Option<&LinkPreview>) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let key = format!("preview:{}", hex::encode(sha2::Sha256::digest(url.as_bytes())));
+        let ttl = if preview.is_some() { 86400 } else { 3600 };
+        con.set_ex::<_, _, ()>(key, serde_json::to_string(&preview)?, ttl).await?;
+        Ok(())
+    }
+
+    pub async fn set_link_preview(&self, item_id: i64, preview: &LinkPreview) -> Result<Option<models::Message>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbMessage,
+                r#"
+                UPDATE public.messages
+                    SET link_preview = $1
+                    FROM public.items
+                    WHERE items.message_id = messages.id AND items.id = $2
+                    RETURNING messages.*
+                "#,
+                serde_json::to_value(preview)?,
+                item_id
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(query.map(|db_message| message_from_db(item_id, db_message)))
+    }
+
+    /// Inserts a new scheduled message; `id` and `created_at` are assigned by the database.
+    pub async fn create_scheduled_message(&self, scheduled_message: &models::ScheduledMessage) -> Result<models::ScheduledMessage, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbScheduledMessage,
+                r#"
+                INSERT INTO public.scheduled_messages (from_id, chat_id, text, entities, reply_to_id, thread_id, ttl, schedule_at)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                    RETURNING *
+                "#,
+                scheduled_message.from_id,
+                scheduled_message.chat_id,
+                scheduled_message.text,
+                serde_json::to_value(&scheduled_message.entities)?,
+                scheduled_message.reply_to_id,
+                scheduled_message.thread_id,
+                scheduled_message.ttl.map(|ttl| ttl as i32),
+                timestamp_to_db(scheduled_message.schedule_at)
+            )
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(scheduled_message_from_db(query))
+    }
+
+    pub async fn get_scheduled_message(&self, id: i64) -> Result<Option<models::ScheduledMessage>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbScheduledMessage,
+                r#"
+                SELECT * FROM public.scheduled_messages
+                    WHERE id = $1
+                "#,
+                id
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(query.map(scheduled_message_from_db))
+    }
+
+    pub async fn get_scheduled_messages(&self, from_id: i64, chat_id: i64) -> Result<Vec<models::ScheduledMessage>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbScheduledMessage,
+                r#"
+                SELECT * FROM public.scheduled_messages
+                    WHERE from_id = $1 AND chat_id = $2
+                    ORDER BY schedule_at
+                "#,
+                from_id,
+                chat_id
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().map(scheduled_message_from_db).collect())
+    }
+
+    pub async fn update_scheduled_message(&self, scheduled_message: &models::ScheduledMessage) -> Result<Option<models::ScheduledMessage>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbScheduledMessage,
+                r#"
+                UPDATE public.scheduled_messages
+                    SET text = $1, entities = $2, reply_to_id = $3, thread_id = $4, ttl = $5, schedule_at = $6,
+                        status = 'pending', last_error = NULL
+                    WHERE id = $7 AND (locked_until IS NULL OR locked_until <= now())
+                    RETURNING *
+                "#,
+                scheduled_message.text,
+                serde_json::to_value(&scheduled_message.entities)?,
+                scheduled_message.reply_to_id,
+                scheduled_message.thread_id,
+                scheduled_message.ttl.map(|ttl| ttl as i32),
+                timestamp_to_db(scheduled_message.schedule_at),
+                scheduled_message.id
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(query.map(scheduled_message_from_db))
+    }
+
+    /// Deletes the scheduled message and returns it, unless it's being delivered right now.
+    pub async fn take_scheduled_message(&self, id: i64) -> Result<Option<models::ScheduledMessage>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbScheduledMessage,
+                r#"
+                DELETE FROM public.scheduled_messages
+                    WHERE id = $1 AND (locked_until IS NULL OR locked_until <= now())
+                    RETURNING *
+                "#,
+                id
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(query.map(scheduled_message_from_db))
+    }
+
+    /// Locks a scheduled message for `lease` seconds so nobody else delivers it meanwhile.
+    /// It stays in the table until `delete_scheduled_message`; if the sender dies, the lease runs out.
+    pub async fn claim_scheduled_message(&self, id: i64, lease: i64) -> Result<Option<models::ScheduledMessage>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbScheduledMessage,
+                r#"
+                UPDATE public.scheduled_messages
+                    SET locked_until = now() + make_interval(secs => $2)
+                    WHERE id = $1 AND (locked_until IS NULL OR locked_until <= now())
+                    RETURNING *
+                "#,
+                id,
+                lease as f64
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(query.map(scheduled_message_from_db))
+    }
+
+    /// Same as `claim_scheduled_message` for a batch of pending messages that are due.
+    pub async fn claim_due_scheduled_messages(&self, limit: i64, lease: i64) -> Result<Vec<models::ScheduledMessage>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbScheduledMessage,
+                r#"
+                UPDATE public.scheduled_messages
+                    SET locked_until = now() + make_interval(secs => $2)
+                    WHERE id IN (
+                        SELECT id FROM public.scheduled_messages
+                            WHERE status = 'pending' AND schedule_at <= now() AND (locked_until IS NULL OR locked_until <= now())
+                            ORDER BY schedule_at
+                            LIMIT $1
+                            FOR UPDATE SKIP LOCKED
+                    )
+                    RETURNING *
+                "#,
+                limit,
+                lease as f64
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().map(scheduled_message_from_db).collect())
+    }
+
+    /// Lets a claimed message be picked up again right away.
+    pub async fn release_scheduled_message(&self, id: i64) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"
+                UPDATE public.scheduled_messages
+                    SET locked_until = NULL
+                    WHERE id = $1
+                "#,
+                id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Keeps a message that can't be delivered, so its sender can see why and fix it.
+    pub async fn mark_scheduled_message_failed(&self, id: i64, error: &str) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"
+                UPDATE public.scheduled_messages
+                    SET status = 'failed', last_error = $2, locked_until = NULL
+                    WHERE id = $1
+                "#,
+                id,
+                error
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_scheduled_message(&self, id: i64) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"
+                DELETE FROM public.scheduled_messages
+                    WHERE id = $1
+                "#,
+                id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Timers belong to the conversation, so both sides of a chat share one row.
+    pub async fn get_chat_ttl(&self, user_id: i64, chat_id: i64) -> Result<Option<u32>, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                SELECT ttl
+                    FROM public.chat_timers
+                    WHERE first_id = LEAST($1::bigint, $2::bigint) AND second_id = GREATEST($1::bigint, $2::bigint)
+                "#,
+                user_id,
+                chat_id
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(query.map(|row| row.ttl as u32))
+    }
+
+    pub async fn set_chat_ttl(&self, user_id: i64, chat_id: i64, ttl: Option<u32>) -> Result<(), StorageError> {
+        if let Some(ttl) = ttl {
+            sqlx::query!(
+                    r#"
+                    INSERT INTO public.chat_timers (first_id, second_id, ttl)
+                        VALUES (LEAST($1::bigint, $2::bigint), GREATEST($1::bigint, $2::bigint), $3)
+                        ON CONFLICT (first_id, second_id) DO UPDATE SET ttl = $3, updated_at = now()
+                    "#,
+                    user_id,
+                    chat_id,
+                    ttl as i32
+                )
+                .execute(&self.pool)
+                .await?;
+        } else {
+            sqlx::query!(
+                    r#"
+                    DELETE FROM public.chat_timers
+                        WHERE first_id = LEAST($1::bigint, $2::bigint) AND second_id = GREATEST($1::bigint, $2::bigint)
+                    "#,
+                    user_id,
+                    chat_id
+                )
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn delete_expired_messages(&self, limit: i64) -> Result<Vec<models::DeletedMessage>, StorageError> {
+        let query = sqlx::query_as!(
+                models::DeletedMessage,
+                r#"
+                WITH expired AS (
+                    SELECT id FROM public.messages
+                        WHERE expires_at <= now()
+                        ORDER BY expires_at
+                        LIMIT $1
+                        FOR UPDATE SKIP LOCKED
+                ), deleted_items AS (
+                    DELETE FROM public.items
+                        WHERE message_id IN (SELECT id FROM expired)
+                        RETURNING id, message_id
+                ), deleted_messages AS (
+                    DELETE FROM public.messages
+                        WHERE id IN (SELECT id FROM expired)
+                        RETURNING id, from_id, chat_id
+                )
+                SELECT deleted_items.id AS "id!", deleted_messages.from_id AS "from_id!", deleted_messages.chat_id AS "chat_id!"
+                    FROM deleted_items
+                    JOIN deleted_messages ON deleted_items.message_id = deleted_messages.id
+                "#,
+                limit
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query)
+    }
+
+    pub async fn save_draft(&self, user_id: i64, chat_id: i64, text: &str, entities: &[MessageEntity], reply_to_id: Option<i64>) -> Result<models::Draft, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbDraft,
+                r#"
+                INSERT INTO public.drafts (user_id, chat_id, text, entities, reply_to_id)
+                    VALUES ($1, $2, $3, $4, $5)
+                    ON CONFLICT (user_id, chat_id) DO UPDATE
+                        SET text = $3, entities = $4, reply_to_id = $5, updated_at = now()
+                    RETURNING chat_id, text, entities, reply_to_id, updated_at
+                "#,
+                user_id,
+                chat_id,
+                text,
+                serde_json::to_value(entities)?,
+                reply_to_id
+            )
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(draft_from_db(query))
+    }
+
+    /// Returns `true` if there was a draft to delete.
+    pub async fn delete_draft(&self, user_id: i64, chat_id: i64) -> Result<bool, StorageError> {
+        let result = sqlx::query!(
+                r#"
+                DELETE FROM public.drafts
+                    WHERE user_id = $1 AND chat_id = $2
+                "#,
+                user_id,
+                chat_id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The chats of the user, their own saved messages included, latest activity first.
+    /// Partners who deleted their account are listed as tombstones.
+    pub async fn get_dialogs(&self, user_id: i64, offset: i64, limit: i64) -> Result<Vec<ViewedDialog>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbDialog,
+                r#"
+                WITH chats AS (
+                    SELECT item_id
+                        FROM public.known
+                        WHERE user_id = $1
+                    UNION
+                    SELECT $1
+                )
+                SELECT items.id AS "item_id!",
+                        users.id AS "id?", users.email AS "email?", users.username AS "username?",
+                        users.first_name AS "first_name?", users.last_name AS "last_name?", users.created_at AS "created_at?",
+                        users.photo_id AS "photo_id?", users.bio AS "bio?", users.birthday AS "birthday?", users.website AS "website?",
+                        users.status_text AS "status_text?", users.status_emoji AS "status_emoji?", users.last_seen_at AS "last_seen_at?",
+                        contacts.first_name AS "contact_first_name?", contacts.last_name AS "contact_last_name?",
+                        EXISTS (SELECT 1 FROM public.contacts AS reverse_contacts WHERE reverse_contacts.user_id = items.id AND reverse_contacts.contact_id = $1) AS "has_viewer_as_contact!",
+                        EXISTS (SELECT 1 FROM public.blocks AS reverse_blocks WHERE reverse_blocks.user_id = items.id AND reverse_blocks.blocked_id = $1) AS "has_blocked_viewer!",
+                        COALESCE((
+                            SELECT json_agg(json_build_object('key', key, 'value', value, 'allow_ids', allow_ids, 'deny_ids', deny_ids))
+                                FROM public.privacy_rules
+                                WHERE privacy_rules.user_id = items.id
+                        ), '[]') AS "privacy_rules!",
+                        last_message.item_id AS "message_item_id?", last_message.id AS "message_id?",
+                        last_message.from_id AS "message_from_id?", last_message.chat_id AS "message_chat_id?",
+                        last_message.text AS "message_text?", last_message.created_at AS "message_created_at?",
+                        last_message.entities AS "message_entities?", last_message.link_preview AS "message_link_preview?",
+                        last_message.action AS "message_action?", last_message.expires_at AS "message_expires_at?",
+                        last_message.reply_to_id AS "message_reply_to_id?", last_message.forward_from_id AS "message_forward_from_id?",
+                        last_message.thread_id AS "message_thread_id?", last_message.reply_count AS "message_reply_count?",
+                        last_message.recent_repliers AS "message_recent_repliers?", last_message.views AS "message_views?",
+                        drafts.text AS "draft_text?", drafts.entities AS "draft_entities?",
+                        drafts.reply_to_id AS "draft_reply_to_id?", drafts.updated_at AS "draft_updated_at?",
+                        (
+                            SELECT COUNT(*)
+                                FROM public.mentions
+                                JOIN public.items AS mention_items ON mention_items.id = mentions.message_id
+                                JOIN public.messages AS mention_messages ON mention_messages.id = mention_items.message_id
+                                WHERE mentions.user_id = $1 AND NOT mentions.read
+                                    AND ((mention_messages.from_id = $1 AND mention_messages.chat_id = items.id)
+                                        OR (mention_messages.from_id = items.id AND mention_messages.chat_id = $1))
+                        ) AS "unread_mentions_count!"
+                    FROM chats
+                    JOIN public.items ON items.id = chats.item_id
+                    LEFT JOIN public.users ON users.id = items.user_id
+                    LEFT JOIN public.contacts ON contacts.user_id = $1 AND contacts.contact_id = items.id
+                    LEFT JOIN LATERAL (
+                        SELECT message_items.id AS item_id, messages.*
+                            FROM public.messages
+                            JOIN public.items AS message_items ON message_items.message_id = messages.id
+                            WHERE (messages.from_id = $1 AND messages.chat_id = items.id)
+                                OR (messages.from_id = items.id AND messages.chat_id = $1)
+                            ORDER BY messages.id DESC
+                            LIMIT 1
+                    ) AS last_message ON true
+                    LEFT JOIN public.drafts ON drafts.user_id = $1 AND drafts.chat_id = items.id
+                    WHERE users.id IS NOT NULL OR items.deleted_at IS NOT NULL
+                    ORDER BY GREATEST(last_message.created_at, drafts.updated_at) DESC NULLS LAST, items.id DESC
+                    OFFSET $2
+                    LIMIT $3
+                "#,
+                user_id,
+                offset,
+                limit
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().map(dialog_from_db).collect())
+    }
+
+    /// Counts a new reply on the thread root and moves its author to the front of the recent repliers.
+    pub async fn add_thread_reply(&self, root_id: i64, from_id: i64) -> Result<Option<models::Message>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbMessage,
+                r#"
+                UPDATE public.messages
+                    SET reply_count = reply_count + 1,
+                        recent_repliers = (array_prepend($1::bigint, array_remove(recent_repliers, $1::bigint)))[1:3]
+                    FROM public.items
+                    WHERE items.message_id = messages.id AND items.id = $2
+                    RETURNING messages.*
+                "#,
+                from_id,
+                root_id
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(query.map(|db_message| message_from_db(root_id, db_message)))
+    }
+
+    pub async fn get_thread_participants(&self, root_id: i64) -> Result<Vec<i64>, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                SELECT DISTINCT from_id
+                    FROM public.messages
+                    WHERE thread_id = $1
+                "#,
+                root_id
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().map(|row| row.from_id).collect())
+    }
+
+    pub async fn get_thread_messages(&self, root_id: i64, offset_id: i64, limit: i64) -> Result<Vec<models::Message>, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                SELECT items.id
+                    FROM public.messages
+                    JOIN public.items ON items.message_id = messages.id
+                    WHERE messages.thread_id = $1 AND items.id > $2
+                    ORDER BY items.id
+                    LIMIT $3
+                "#,
+                root_id,
+                offset_id,
+                limit
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        let mut messages = Vec::new();
+        for row in query {
+            if let Some(message) = self.get_message(row.id).await? {
+                messages.push(message);
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Remembers that the user saw the messages whose flag is set, counting each viewer once, and returns
+    /// for every message the views that were counted in Redis but haven't reached Postgres yet.
+    /// Runs as one script, so a view is never remembered without being counted.
+    /// Viewers are remembered for `VIEWERS_TTL` after the last view of the message only, so a user who
+    /// comes back to a message after that is counted again.
+    pub async fn add_views(&self, user_id: i64, views: &[(i64, bool)]) -> Result<Vec<i64>, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let script = redis::Script::new(r#"
+                local pending = {}
+                for i = 3, #KEYS do
+                    local item_id = ARGV[2 * i - 3]
+                    if ARGV[2 * i - 2] == '1' and redis.call('SADD', KEYS[i], ARGV[1]) == 1 then
+                        redis.call('EXPIRE', KEYS[i], ARGV[2])
+                        redis.call('HINCRBY', KEYS[1], item_id, 1)
+                    end
+                    local count = tonumber(redis.call('HGET', KEYS[1], item_id) or 0) + tonumber(redis.call('HGET', KEYS[2], item_id) or 0)
+                    table.insert(pending, count)
+                end
+                return pending
+            "#);
+        let mut invocation = script.prepare_invoke();
+        invocation.key("views:pending").key("views:flushing").arg(user_id).arg(VIEWERS_TTL);
+        for (item_id, count) in views {
+            invocation.key(format!("views:{}", item_id)).arg(item_id).arg(if *count { 1 } else { 0 });
+        }
+        Ok(invocation.invoke_async(&mut con).await?)
+    }
+
+    /// Moves the pending counters aside under a batch id, so new views keep counting while these are written.
+    /// A batch left over by an interrupted flush is returned first, with the id it had.
+    pub async fn take_pending_views(&self) -> Result<Option<(String, Vec<(i64, i64)>)>, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let reply: Vec<String> = redis::Script::new(r#"
+                if redis.call('EXISTS', KEYS[2]) == 0 then
+                    if redis.call('EXISTS', KEYS[1]) == 0 then
+                        return {}
+                    end
+                    redis.call('RENAME', KEYS[1], KEYS[2])
+                end
+                redis.call('SET', KEYS[3], ARGV[1], 'NX')
+                local batch = redis.call('HGETALL', KEYS[2])
+                table.insert(batch, 1, redis.call('GET', KEYS[3]))
+                return batch
+            "#)
+            .key("views:pending")
+            .key("views:flushing")
+            .key("views:flushing:batch")
+            .arg(random::random_word(16))
+            .invoke_async(&mut con)
+            .await?;
+        let Some((batch_id, views)) = reply.split_first() else {
+            return Ok(None);
+        };
+        let views = views
+            .chunks_exact(2)
+            .filter_map(|view| Some((view[0].parse().ok()?, view[1].parse().ok()?)))
+            .collect();
+        Ok(Some((batch_id.clone(), views)))
+    }
+
+    pub async fn finish_pending_views(&self) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let _:() = con.del(&["views:flushing", "views:flushing:batch"]).await?;
+        Ok(())
+    }
+
+    /// Adds a batch of views at most once: the batch id is recorded in the same transaction,
+    /// so retrying a batch that was already written does nothing.
+    pub async fn add_message_views(&self, batch_id: &str, views: &[(i64, i64)]) -> Result<(), StorageError> {
+        let (item_ids, counts): (Vec<i64>, Vec<i64>) = views.iter().copied().unzip();
+        let mut transaction = self.pool.begin().await?;
+        let inserted = sqlx::query!(
+                r#"
+                INSERT INTO public.view_batches (id)
+                    VALUES ($1)
+                    ON CONFLICT (id) DO NOTHING
+                "#,
+                batch_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+        if inserted.rows_affected() == 0 {
+            return Ok(());
+        }
+        sqlx::query!(
+                r#"
+                UPDATE public.messages
+                    SET views = messages.views + pending.count
+                    FROM public.items, unnest($1::bigint[], $2::bigint[]) AS pending(item_id, count)
+                    WHERE items.message_id = messages.id AND items.id = pending.item_id
+                "#,
+                &item_ids,
+                &counts
+            )
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query!("DELETE FROM public.view_batches WHERE applied_at < now() - interval '1 day'").execute(&mut *transaction).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    pub async fn create_photo(&self, user_id: i64) -> Result<models::Photo, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbPhoto,
+                r#"
+                INSERT INTO public.photos (user_id)
+                    VALUES ($1)
+                    RETURNING *
+                "#,
+                user_id
+            )
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(photo_from_db(query))
+    }
+
+    pub async fn get_photo(&self, id: i64) -> Result<Option<models::Photo>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbPhoto,
+                r#"
+                SELECT public.photos.*
+                    FROM public.photos
+                    WHERE id = $1
+                "#,
+                id
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(query.map(photo_from_db))
+    }
+
+    /// Returns the photos of a user, newest first.
+    pub async fn get_photos(&self, user_id: i64) -> Result<Vec<models::Photo>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbPhoto,
+                r#"
+                SELECT public.photos.*
+                    FROM public.photos
+                    WHERE user_id = $1
+                    ORDER BY id DESC
+                "#,
+                user_id
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().map(photo_from_db).collect())
+    }
+
+    pub async fn delete_photo(&self, id: i64) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"
+                DELETE FROM public.photos
+                    WHERE id = $1
+                "#,
+                id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_user_photo(&self, user_id: i64, photo_id: Option<i64>) -> Result<Option<models::User>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbUser,
+                r#"
+                UPDATE public.users
+                    SET photo_id = $1
+                    FROM public.items
+                    WHERE items.user_id = users.id AND items.id = $2
+                    RETURNING users.*
+                "#,
+                photo_id,
+                user_id
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(query.map(|db_user| user_from_db(user_id, db_user)))
+    }
+
+    pub async fn set_last_seen(&self, user_id: i64) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"
+                UPDATE public.users
+                    SET last_seen_at = now()
+                    FROM public.items
+                    WHERE items.user_id = users.id AND items.id = $1
+                "#,
+                user_id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_last_seen(&self, user_id: i64) -> Result<Option<usize>, StorageError> {
+        let db_user = self.get_db_user(user_id).await?;
+        Ok(db_user
+            .and_then(|db_user| db_user.last_seen_at)
+            .map(|last_seen_at| last_seen_at.and_utc().timestamp() as usize))
+    }
+
+    pub async fn get_privacy_rules(&self, user_id: i64) -> Result<Vec<models::PrivacyRule>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbPrivacyRule,
+                r#"
+                SELECT key, value, allow_ids, deny_ids
+                    FROM public.privacy_rules
+                    WHERE user_id = $1
+                "#,
+                user_id
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().filter_map(privacy_rule_from_db).collect())
+    }
+
+    pub async fn set_privacy_rule(&self, user_id: i64, privacy_rule: &models::PrivacyRule) -> Result<models::PrivacyRule, StorageError> {
+        let key = serde_json::to_value(privacy_rule.key)?;
+        let value = serde_json::to_value(privacy_rule.value)?;
+        let query = sqlx::query_as!(
+                types::DbPrivacyRule,
+                r#"
+                INSERT INTO public.privacy_rules (user_id, key, value, allow_ids, deny_ids)
+                    VALUES ($1, $2, $3, $4, $5)
+                    ON CONFLICT (user_id, key) DO UPDATE
+                    SET value = EXCLUDED.value, allow_ids = EXCLUDED.allow_ids, deny_ids = EXCLUDED.deny_ids
+                    RETURNING key, value, allow_ids, deny_ids
+                "#,
+                user_id,
+                key.as_str(),
+                value.as_str(),
+                &privacy_rule.allow_ids,
+                &privacy_rule.deny_ids
+            )
+            .fetch_one(&self.pool)
+            .await?;
+        privacy_rule_from_db(query).ok_or(StorageError::Internal)
+    }
+
+    pub async fn block_user(&self, user_id: i64, blocked_id: i64) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"
+                INSERT INTO public.blocks (user_id, blocked_id)
+                    VALUES ($1, $2)
+                    ON CONFLICT (user_id, blocked_id) DO NOTHING
+                "#,
+                user_id,
+                blocked_id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn unblock_user(&self, user_id: i64, blocked_id: i64) -> Result<bool, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                DELETE FROM public.blocks
+                    WHERE user_id = $1 AND blocked_id = $2
+                "#,
+                user_id,
+                blocked_id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(query.rows_affected() > 0)
+    }
+
+    pub async fn is_blocked(&self, user_id: i64, blocked_id: i64) -> Result<bool, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                SELECT EXISTS (
+                    SELECT 1
+                    FROM public.blocks
+                    WHERE user_id = $1 AND blocked_id = $2
+                )
+                "#,
+                user_id,
+                blocked_id
+            )
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(query.exists.unwrap_or(false))
+    }
+
+    /// Returns blocked users as the user sees them, with the time they were blocked, most recent first.
+    /// Deleted accounts are left out.
+    pub async fn get_blocked_users(&self, user_id: i64, offset: i64, limit: i64) -> Result<Vec<(ViewedUser, usize)>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbListedUser,
+                r#"
+                SELECT items.id AS "item_id!", users.*,
+                        contacts.first_name AS "contact_first_name?", contacts.last_name AS "contact_last_name?",
+                        EXISTS (SELECT 1 FROM public.contacts AS reverse_contacts WHERE reverse_contacts.user_id = items.id AND reverse_contacts.contact_id = $1) AS "has_viewer_as_contact!",
+                        EXISTS (SELECT 1 FROM public.blocks AS reverse_blocks WHERE reverse_blocks.user_id = items.id AND reverse_blocks.blocked_id = $1) AS "has_blocked_viewer!",
+                        COALESCE((
+                            SELECT json_agg(json_build_object('key', key, 'value', value, 'allow_ids', allow_ids, 'deny_ids', deny_ids))
+                                FROM public.privacy_rules
+                                WHERE privacy_rules.user_id = items.id
+                        ), '[]') AS "privacy_rules!",
+                        blocks.created_at AS listed_at
+                    FROM public.blocks
+                    JOIN public.items ON items.id = blocks.blocked_id
+                    JOIN public.users ON users.id = items.user_id
+                    LEFT JOIN public.contacts ON contacts.user_id = $1 AND contacts.contact_id = items.id
+                    WHERE blocks.user_id = $1
+                    ORDER BY blocks.id DESC
+                    OFFSET $2
+                    LIMIT $3
+                "#,
+                user_id,
+                offset,
+                limit
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().map(listed_user_from_db).collect())
+    }
+
+    pub async fn save_contact(&self, user_id: i64, contact_id: i64, name: &models::ContactName) -> Result<usize, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbContact,
+                r#"
+                INSERT INTO public.contacts (user_id, contact_id, first_name, last_name)
+                    VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (user_id, contact_id) DO UPDATE
+                    SET first_name = EXCLUDED.first_name, last_name = EXCLUDED.last_name
+                    RETURNING first_name, last_name, created_at
+                "#,
+                user_id,
+                contact_id,
+                name.first_name,
+                name.last_name
+            )
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(query.created_at.and_utc().timestamp() as usize)
+    }
+
+    pub async fn delete_contact(&self, user_id: i64, contact_id: i64) -> Result<bool, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                DELETE FROM public.contacts
+                    WHERE user_id = $1 AND contact_id = $2
+                "#,
+                user_id,
+                contact_id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(query.rows_affected() > 0)
+    }
+
+    pub async fn get_contact_name(&self, user_id: i64, contact_id: i64) -> Result<Option<models::ContactName>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbContact,
+                r#"
+                SELECT first_name, last_name, created_at
+                    FROM public.contacts
+                    WHERE user_id = $1 AND contact_id = $2
+                "#,
+                user_id,
+                contact_id
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(query.map(|db_contact| models::ContactName { first_name: db_contact.first_name, last_name: db_contact.last_name }))
+    }
+
+    pub async fn is_contact(&self, user_id: i64, contact_id: i64) -> Result<bool, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                SELECT EXISTS (
+                    SELECT 1
+                    FROM public.contacts
+                    WHERE user_id = $1 AND contact_id = $2
+                )
+                "#,
+                user_id,
+                contact_id
+            )
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(query.exists.unwrap_or(false))
+    }
+
+    /// Returns contacts as the user sees them, with the time they were added, sorted by the name the user chose.
+    /// Deleted accounts are left out.
+    pub async fn get_contacts(&self, user_id: i64, offset: i64, limit: i64) -> Result<Vec<(ViewedUser, usize)>, StorageError> {
+        let query = sqlx::query_as!(
+                types::DbListedUser,
+                r#"
+                SELECT items.id AS "item_id!", users.*,
+                        contacts.first_name AS "contact_first_name?", contacts.last_name AS "contact_last_name?",
+                        EXISTS (SELECT 1 FROM public.contacts AS reverse_contacts WHERE reverse_contacts.user_id = items.id AND reverse_contacts.contact_id = $1) AS "has_viewer_as_contact!",
+                        EXISTS (SELECT 1 FROM public.blocks AS reverse_blocks WHERE reverse_blocks.user_id = items.id AND reverse_blocks.blocked_id = $1) AS "has_blocked_viewer!",
+                        COALESCE((
+                            SELECT json_agg(json_build_object('key', key, 'value', value, 'allow_ids', allow_ids, 'deny_ids', deny_ids))
+                                FROM public.privacy_rules
+                                WHERE privacy_rules.user_id = items.id
+                        ), '[]') AS "privacy_rules!",
+                        contacts.created_at AS listed_at
+                    FROM public.contacts
+                    JOIN public.items ON items.id = contacts.contact_id
+                    JOIN public.users ON users.id = items.user_id
+                    WHERE contacts.user_id = $1
+                    ORDER BY LOWER(contacts.first_name), LOWER(contacts.last_name), contacts.id
+                    OFFSET $2
+                    LIMIT $3
+                "#,
+                user_id,
+                offset,
+                limit
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().map(listed_user_from_db).collect())
+    }
+
+    /// Everyone who should hear about changes of the user: chat partners and users who have them in contacts.
+    pub async fn get_watchers(&self, user_id: i64) -> Result<Vec<i64>, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                SELECT item_id AS "id!"
+                    FROM public.known
+                    WHERE user_id = $1
+                UNION
+                SELECT user_id AS "id!"
+                    FROM public.contacts
+                    WHERE contact_id = $1
+                "#,
+                user_id
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().map(|row| row.id).collect())
+    }
+
+    /// Like `get_user`, but returns the "Deleted Account" tombstone for deleted users,
+    /// for places that show chats rather than act on them.
+    pub async fn get_user_or_deleted(&self, item_id: i64) -> Result<Option<models::User>, StorageError> {
+        if let Some(user) = self.get_user(item_id).await? {
+            return Ok(Some(user));
+        }
+        let item = sqlx::query_as!(
+                types::DbItem,
+                r#"SELECT * FROM public.items WHERE id = $1"#,
+                item_id
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(item.filter(|item| item.deleted_at.is_some()).map(|item| deleted_user(item.id)))
+    }
+
+    /// Drops the user row and everything that belongs to the user only, sessions included, keeping the item as a tombstone.
+    /// Messages of the user are deleted too if asked. Otherwise they are kept as they are, text included,
+    /// because they are part of the chat partners' history; they are anonymised by their author becoming
+    /// the tombstone, which carries no personal data.
+    pub async fn delete_account(&self, user_id: i64, delete_messages: bool) -> Result<DeletedAccount, StorageError> {
+        let mut transaction = self.pool.begin().await?;
+        let mut deleted_messages = Vec::new();
+        if delete_messages {
+            deleted_messages = sqlx::query_as!(
+                    models::DeletedMessage,
+                    r#"
+                    WITH own AS (
+                        SELECT id FROM public.messages
+                            WHERE from_id = $1
+                            FOR UPDATE
+                    ), deleted_items AS (
+                        DELETE FROM public.items
+                            WHERE message_id IN (SELECT id FROM own)
+                            RETURNING id, message_id
+                    ), deleted_messages AS (
+                        DELETE FROM public.messages
+                            WHERE id IN (SELECT id FROM own)
+                            RETURNING id, from_id, chat_id
+                    )
+                    SELECT deleted_items.id AS "id!", deleted_messages.from_id AS "from_id!", deleted_messages.chat_id AS "chat_id!"
+                        FROM deleted_items
+                        JOIN deleted_messages ON deleted_items.message_id = deleted_messages.id
+                    "#,
+                    user_id
+                )
+                .fetch_all(&mut *transaction)
+                .await?;
+        }
+        sqlx::query!("DELETE FROM public.known WHERE user_id = $1", user_id).execute(&mut *transaction).await?;
+        sqlx::query!("DELETE FROM public.drafts WHERE user_id = $1", user_id).execute(&mut *transaction).await?;
+        sqlx::query!("DELETE FROM public.mentions WHERE user_id = $1", user_id).execute(&mut *transaction).await?;
+        sqlx::query!("DELETE FROM public.scheduled_messages WHERE from_id = $1", user_id).execute(&mut *transaction).await?;
+        sqlx::query!("DELETE FROM public.privacy_rules WHERE user_id = $1", user_id).execute(&mut *transaction).await?;
+        sqlx::query!("DELETE FROM public.contacts WHERE user_id = $1 OR contact_id = $1", user_id).execute(&mut *transaction).await?;
+        sqlx::query!("DELETE FROM public.blocks WHERE user_id = $1 OR blocked_id = $1", user_id).execute(&mut *transaction).await?;
+        sqlx::query!("DELETE FROM public.passwords WHERE user_id = $1", user_id).execute(&mut *transaction).await?;
+        let session_ids = sqlx::query_scalar!("DELETE FROM public.sessions WHERE user_id = $1 RETURNING id", user_id)
+            .fetch_all(&mut *transaction)
+            .await?;
+        let item = sqlx::query_as!(
+                types::DbItem,
+                r#"
+                UPDATE public.items
+                    SET user_id = NULL, deleted_at = now()
+                    FROM public.items AS old_items
+                    WHERE items.id = old_items.id AND items.id = $1
+                    RETURNING old_items.*
+                "#,
+                user_id
+            )
+            .fetch_one(&mut *transaction)
+            .await?;
+        sqlx::query!("DELETE FROM public.users WHERE id = $1", item.user_id).execute(&mut *transaction).await?;
+        let photo_ids = sqlx::query_scalar!("DELETE FROM public.photos WHERE user_id = $1 RETURNING id", user_id)
+            .fetch_all(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(DeletedAccount { messages: deleted_messages, session_ids, photo_ids })
+    }
+
+    pub async fn store_email_change(&self, user_id: i64, email_change: &EmailChange, lifetime: u64) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        con.set_ex::<_, _, ()>(format!("email_change:{}", user_id), serde_json::to_string(email_change)?, lifetime).await?;
+        Ok(())
+    }
+
+    pub async fn get_email_change(&self, user_id: i64) -> Result<Option<EmailChange>, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let email_change: Option<String> = con.get(format!("email_change:{}", user_id)).await?;
+        match email_change {
+            Some(email_change) => Ok(Some(serde_json::from_str(&email_change)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Also forgets the wrong guesses made against the codes.
+    pub async fn delete_email_change(&self, user_id: i64) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let _:() = con.del(&[format!("email_change:{}", user_id), format!("email_change_attempts:{}", user_id)]).await?;
+        Ok(())
+    }
+
+    /// Counts a guess against the pending email change and returns how many were made.
+    pub async fn add_email_change_attempt(&self, user_id: i64) -> Result<i64, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let key = format!("email_change_attempts:{}", user_id);
+        let attempts: i64 = con.incr(&key, 1).await?;
+        if attempts == 1 {
+            let _: () = con.expire(&key, 600).await?;
+        }
+        Ok(attempts)
+    }
+
+    pub async fn add_email_change_send(&self, user_id: i64, window: i64) -> Result<i64, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let key = format!("email_change_sends:{}", user_id);
+        let sends: i64 = con.incr(&key, 1).await?;
+        let _: () = con.expire(&key, window).await?;
+        Ok(sends)
+    }
+
+    pub async fn get_email_change_sends(&self, user_id: i64) -> Result<i64, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let sends: Option<i64> = con.get(format!("email_change_sends:{}", user_id)).await?;
+        Ok(sends.unwrap_or(0))
+    }
+
+    /// Returns `false` if another account already uses the email.
+    pub async fn update_email(&self, user_id: i64, email: &str) -> Result<bool, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                UPDATE public.users
+                    SET email = $1
+                    FROM public.items
+                    WHERE items.user_id = users.id AND items.id = $2
+                "#,
+                email,
+                user_id
+            )
+            .execute(&self.pool)
+            .await;
+        match query {
+            Ok(_) => Ok(true),
+            Err(sqlx::Error::Database(database_error)) if database_error.is_unique_violation() => Ok(false),
+            Err(sqlx_error) => Err(sqlx_error.into()),
+        }
+    }
+
+    pub async fn create_session(&self, user_id: i64, new_session: &NewSession<'_>) -> Result<models::Session, StorageError> {
+        let db_session = sqlx::query_as!(
+                types::DbSession,
+                r#"
+                INSERT INTO public.sessions (user_id, device_name, ip, user_agent)
+                    VALUES ($1, $2, $3, $4)
+                    RETURNING id, device_name, ip, user_agent, created_at, last_active_at
+                "#,
+                user_id,
+                new_session.device_name,
+                new_session.ip,
+                new_session.user_agent
+            )
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(session_from_db(db_session))
+    }
+
+    pub async fn get_session(&self, session_id: i64, user_id: i64) -> Result<Option<models::Session>, StorageError> {
+        let db_session = sqlx::query_as!(
+                types::DbSession,
+                r#"SELECT id, device_name, ip, user_agent, created_at, last_active_at FROM public.sessions WHERE id = $1 AND user_id = $2"#,
+                session_id,
+                user_id
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(db_session.map(session_from_db))
+    }
+
+    pub async fn get_sessions(&self, user_id: i64) -> Result<Vec<models::Session>, StorageError> {
+        let db_sessions = sqlx::query_as!(
+                types::DbSession,
+                r#"SELECT id, device_name, ip, user_agent, created_at, last_active_at FROM public.sessions WHERE user_id = $1 ORDER BY last_active_at DESC"#,
+                user_id
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(db_sessions.into_iter().map(session_from_db).collect())
+    }
+
+    pub async fn touch_session(&self, session_id: i64) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"UPDATE public.sessions SET last_active_at = now() WHERE id = $1"#,
+                session_id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_session(&self, session_id: i64, user_id: i64) -> Result<bool, StorageError> {
+        let result = sqlx::query!(
+                r#"DELETE FROM public.sessions WHERE id = $1 AND user_id = $2"#,
+                session_id,
+                user_id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Deletes every session of the user except `keep_id`, or all of them when it's `None`, and returns their ids.
+    pub async fn delete_sessions(&self, user_id: i64, keep_id: Option<i64>) -> Result<Vec<i64>, StorageError> {
+        let query = sqlx::query!(
+                r#"DELETE FROM public.sessions WHERE user_id = $1 AND id IS DISTINCT FROM $2 RETURNING id"#,
+                user_id,
+                keep_id
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().map(|row| row.id).collect())
+    }
+
+    /// Deletes the sessions without any unexpired refresh token, so they can't be resumed anymore,
+    /// and returns their ids. Sessions younger than `grace` seconds are kept, since their first token
+    /// is issued right after them.
+    pub async fn delete_expired_sessions(&self, grace: i64) -> Result<Vec<i64>, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                DELETE FROM public.sessions
+                    WHERE created_at < now() - make_interval(secs => $1)
+                        AND NOT EXISTS (
+                            SELECT 1 FROM public.refresh_tokens
+                                WHERE refresh_tokens.session_id = sessions.id AND refresh_tokens.expires_at > now()
+                        )
+                    RETURNING id
+                "#,
+                grace as f64
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(query.into_iter().map(|row| row.id).collect())
+    }
+
+    /// Also drops the session's refresh tokens that have expired, used or not.
+    pub async fn create_refresh_token(&self, session_id: i64, token_hash: &str, lifetime: u64) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"DELETE FROM public.refresh_tokens WHERE session_id = $1 AND expires_at < now()"#,
+                session_id
+            )
+            .execute(&self.pool)
+            .await?;
+        sqlx::query!(
+                r#"
+                INSERT INTO public.refresh_tokens (session_id, token_hash, expires_at)
+                    VALUES ($1, $2, now() + make_interval(secs => $3))
+                "#,
+                session_id,
+                token_hash,
+                lifetime as f64
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Marks an unused, unexpired refresh token as used and returns its session id and user id.
+    pub async fn use_refresh_token(&self, token_hash: &str) -> Result<Option<(i64, i64)>, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                UPDATE public.refresh_tokens
+                    SET used_at = now()
+                    FROM public.sessions
+                    WHERE sessions.id = refresh_tokens.session_id
+                        AND refresh_tokens.token_hash = $1
+                        AND refresh_tokens.used_at IS NULL
+                        AND refresh_tokens.expires_at > now()
+                    RETURNING sessions.id, sessions.user_id
+                "#,
+                token_hash
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(query.map(|query| (query.id, query.user_id)))
+    }
+
+    /// Deletes the session a used refresh token belongs to and returns its id. Returns `None` if the token was never used.
+    pub async fn revoke_refresh_token_family(&self, token_hash: &str) -> Result<Option<i64>, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                DELETE FROM public.sessions
+                    WHERE id = (SELECT session_id FROM public.refresh_tokens WHERE token_hash = $1 AND used_at IS NOT NULL)
+                    RETURNING id
+                "#,
+                token_hash
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(query.map(|row| row.id))
+    }
+
+    pub async fn get_password(&self, user_id: i64) -> Result<Option<types::DbPassword>, StorageError> {
+        let db_password = sqlx::query_as!(
+                types::DbPassword,
+                r#"SELECT password_hash, hint, recovery_email FROM public.passwords WHERE user_id = $1"#,
+                user_id
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(db_password)
+    }
+
+    pub async fn set_password(&self, user_id: i64, password_hash: &str, hint: Option<&str>, recovery_email: Option<&str>) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"
+                INSERT INTO public.passwords (user_id, password_hash, hint, recovery_email)
+                    VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (user_id) DO UPDATE
+                    SET password_hash = EXCLUDED.password_hash, hint = EXCLUDED.hint, recovery_email = EXCLUDED.recovery_email
+                "#,
+                user_id,
+                password_hash,
+                hint,
+                recovery_email
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_password(&self, user_id: i64) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"DELETE FROM public.passwords WHERE user_id = $1"#,
+                user_id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Counts a password attempt and returns how many were made within the window.
+    pub async fn add_password_attempt(&self, user_id: i64, window: i64) -> Result<i64, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let key = format!("password_attempts:{}", user_id);
+        let attempts: i64 = con.incr(&key, 1).await?;
+        if attempts == 1 {
+            let _: () = con.expire(&key, window).await?;
+        }
+        Ok(attempts)
+    }
+
+    pub async fn reset_password_attempts(&self, user_id: i64) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let _: () = con.del(format!("password_attempts:{}", user_id)).await?;
+        Ok(())
+    }
+
+    pub async fn store_pending_login(&self, token_hash: &str, pending_login: &PendingLogin) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        con.set_ex::<_, _, ()>(format!("password_login:{}", token_hash), serde_json::to_string(pending_login)?, 600).await?;
+        Ok(())
+    }
+
+    pub async fn get_pending_login(&self, token_hash: &str) -> Result<Option<PendingLogin>, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let pending_login: Option<String> = con.get(format!("password_login:{}", token_hash)).await?;
+        match pending_login {
+            Some(pending_login) => Ok(Some(serde_json::from_str(&pending_login)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete_pending_login(&self, token_hash: &str) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let _: () = con.del(format!("password_login:{}", token_hash)).await?;
+        Ok(())
+    }
+
+    pub async fn store_pending_signup(&self, token_hash: &str, pending_login: &PendingLogin, lifetime: u64) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        con.set_ex::<_, _, ()>(format!("signup:{}", token_hash), serde_json::to_string(pending_login)?, lifetime).await?;
+        Ok(())
+    }
+
+    pub async fn get_pending_signup(&self, token_hash: &str) -> Result<Option<PendingLogin>, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let pending_login: Option<String> = con.get(format!("signup:{}", token_hash)).await?;
+        match pending_login {
+            Some(pending_login) => Ok(Some(serde_json::from_str(&pending_login)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete_pending_signup(&self, token_hash: &str) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let _: () = con.del(format!("signup:{}", token_hash)).await?;
+        Ok(())
+    }
+
+    pub async fn store_recovery_otp(&self, user_id: i64, otp: &HashedOtp, lifetime: u64) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        con.set_ex::<_, _, ()>(format!("password_recovery:{}", user_id), serde_json::to_string(otp)?, lifetime).await?;
+        Ok(())
+    }
+
+    pub async fn get_recovery_otp(&self, user_id: i64) -> Result<Option<HashedOtp>, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let otp: Option<String> = con.get(format!("password_recovery:{}", user_id)).await?;
+        match otp {
+            Some(otp) => Ok(Some(serde_json::from_str(&otp)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete_recovery_otp(&self, user_id: i64) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let _: () = con.del(format!("password_recovery:{}", user_id)).await?;
+        Ok(())
+    }
+
+    pub async fn store_recovery_email_change(&self, user_id: i64, recovery_email_change: &RecoveryEmailChange, lifetime: u64) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        con.set_ex::<_, _, ()>(format!("recovery_email:{}", user_id), serde_json::to_string(recovery_email_change)?, lifetime).await?;
+        Ok(())
+    }
+
+    pub async fn get_recovery_email_change(&self, user_id: i64) -> Result<Option<RecoveryEmailChange>, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let recovery_email_change: Option<String> = con.get(format!("recovery_email:{}", user_id)).await?;
+        match recovery_email_change {
+            Some(recovery_email_change) => Ok(Some(serde_json::from_str(&recovery_email_change)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete_recovery_email_change(&self, user_id: i64) -> Result<(), StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let _: () = con.del(format!("recovery_email:{}", user_id)).await?;
+        Ok(())
+    }
+
+    /// Returns `false` if the user has no password to attach the email to.
+    pub async fn set_recovery_email(&self, user_id: i64, recovery_email: &str) -> Result<bool, StorageError> {
+        let query = sqlx::query!(
+                r#"UPDATE public.passwords SET recovery_email = $2 WHERE user_id = $1"#,
+                user_id,
+                recovery_email
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(query.rows_affected() > 0)
+    }
+
+    /// Counts a code sent to the email within the window and returns how many were sent.
+    pub async fn add_otp_send(&self, email: &str, window: i64) -> Result<i64, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let key = format!("otp_sends:{}", email);
+        let sends: i64 = con.incr(&key, 1).await?;
+        let _: () = con.expire(&key, window).await?;
+        Ok(sends)
+    }
+
+    pub async fn get_otp_sends(&self, email: &str) -> Result<i64, StorageError> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let sends: Option<i64> = con.get(format!("otp_sends:{}", email)).await?;
+        Ok(sends.unwrap_or(0))
+    }
+
+    /// An email with `expires_in` is useless after that many seconds and won't be sent late.
+    pub async fn enqueue_email(&self, recipient: &str, subject: &str, text_body: &str, html_body: &str, expires_in: Option<u64>) -> Result<i64, StorageError> {
+        let query = sqlx::query!(
+                r#"
+                INSERT INTO public.email_outbox (recipient, subject, text_body, html_body, expires_at)
+                    VALUES ($1, $2, $3, $4, now() + make_interval(secs => $5))
+                    RETURNING id
+                "#,
+                recipient,
+                subject,
+                text_body,
+                html_body,
+                expires_in.map(|expires_in| expires_in as f64)
+            )
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(query.id)
+    }
+
+    /// Takes up to `limit` due emails and pushes their next attempt `lease` seconds ahead,
+    /// so a crashed worker's emails are retried and concurrent workers don't send twice.
+    /// Emails that expired while waiting are marked as such first and never handed out.
+    pub async fn claim_due_emails(&self, limit: i64, lease: i64) -> Result<Vec<types::DbOutboxEmail>, StorageError> {
+        sqlx::query!(
+                r#"
+                UPDATE public.email_outbox
+                    SET status = 'expired', text_body = NULL, html_body = NULL
+                    WHERE status = 'pending' AND expires_at <= now()
+                "#
+            )
+            .execute(&self.pool)
+            .await?;
+        let db_outbox_emails = sqlx::query_as!(
+                types::DbOutboxEmail,
+                r#"
+                UPDATE public.email_outbox
+                    SET next_attempt_at = now() + make_interval(secs => $2)
+                    WHERE id IN (
+                        SELECT id FROM public.email_outbox
+                            WHERE status = 'pending' AND next_attempt_at <= now() AND (expires_at IS NULL OR expires_at > now())
+                            ORDER BY next_attempt_at
+                            LIMIT $1
+                            FOR UPDATE SKIP LOCKED
+                    )
+                    RETURNING *
+                "#,
+                limit,
+                lease as f64
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(db_outbox_emails)
+    }
+
+    /// The bodies are dropped once an email is done with, as they may hold login codes.
+    pub async fn mark_email_sent(&self, id: i64) -> Result<(), StorageError> {
+        sqlx::query!(
+                r#"
+                UPDATE public.email_outbox
+                    SET status = 'sent', attempts = attempts + 1, sent_at = now(), last_error = NULL, text_body = NULL, html_body = NULL
+                    WHERE id = $1
+                "#,
+                id
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Schedules a retry after `retry_in` seconds, or dead-letters the email when it's `None`.
+    pub async fn mark_email_failed(&self, id: i64, error: &str, retry_in: Option<u64>) -> Result<(), StorageError> {
+        match retry_in {
+            Some(retry_in) => sqlx::query!(
+                    r#"
+                    UPDATE public.email_outbox
+                        SET attempts = attempts + 1, last_error = $2, next_attempt_at = now() + make_interval(secs => $3)
+                        WHERE id = $1
+                    "#,
+                    id,
+                    error,
+                    retry_in as f64
+                )
+                .execute(&self.pool)
+                .await?,
+            None => sqlx::query!(
+                    r#"
+                    UPDATE public.email_outbox
+                        SET status = 'dead', attempts = attempts + 1, last_error = $2, text_body = NULL, html_body = NULL
+                        WHERE id = $1
+                    "#,
+                    id,
+                    error
+                )
+                .execute(&self.pool)
+                .await?,
+        };
+        Ok(())
+    }
+
+    pub async fn get_email_deliveries(&self, recipient: &str, limit: i64) -> Result<Vec<models::EmailDelivery>, StorageError> {
+        let db_outbox_emails = sqlx::query_as!(
+                types::DbOutboxEmail,
+                r#"SELECT * FROM public.email_outbox WHERE recipient = $1 ORDER BY created_at DESC LIMIT $2"#,
+                recipient,
+                limit
+            )
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(db_outbox_emails.into_iter().filter_map(email_delivery_from_db).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs against the database in `DATABASE_URL`, the one the queries are checked against.
+    async fn storage() -> Storage {
+        dotenv::dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let pool = sqlx::PgPool::connect(&database_url).await.expect("Failed to connect to the database");
+        let redis = redis::Client::open("redis://127.0.0.1/").unwrap();
+        Storage { pool, redis }
+    }
+
+    async fn create_session(storage: &Storage, user_id: i64, age: i64) -> i64 {
+        let new_session = NewSession { device_name: None, ip: None, user_agent: None };
+        let session = storage.create_session(user_id, &new_session).await.unwrap();
+        sqlx::query!(
+                r#"UPDATE public.sessions SET created_at = now() - make_interval(secs => $2) WHERE id = $1"#,
+                session.id,
+                age as f64
+            )
+            .execute(&storage.pool)
+            .await
+            .unwrap();
+        session.id
+    }
+
+    #[tokio::test]
+    async fn delete_expired_sessions_keeps_live_and_new_sessions() {
+        let storage = storage().await;
+        let email = format!("{}@example.com", random::random_word(16));
+        let user_id = storage.create_user(&email, None, "Sessions", None).await.unwrap().id;
+        let without_tokens = create_session(&storage, user_id, 7200).await;
+        let with_expired_token = create_session(&storage, user_id, 7200).await;
+        storage.create_refresh_token(with_expired_token, &random::random_word(16), 60).await.unwrap();
+        sqlx::query!(
+                r#"UPDATE public.refresh_tokens SET expires_at = now() - interval '1 minute' WHERE session_id = $1"#,
+                with_expired_token
+            )
+            .execute(&storage.pool)
+            .await
+            .unwrap();
+        let with_live_token = create_session(&storage, user_id, 7200).await;
+        storage.create_refresh_token(with_live_token, &random::random_word(16), 3600).await.unwrap();
+        let new = create_session(&storage, user_id, 0).await;
+
+        let deleted = storage.delete_expired_sessions(3600).await.unwrap();
+        assert!(deleted.contains(&without_tokens));
+        assert!(deleted.contains(&with_expired_token));
+        assert!(!deleted.contains(&with_live_token));
+        assert!(!deleted.contains(&new));
+
+        let sessions: Vec<i64> = storage.get_sessions(user_id).await.unwrap().into_iter().map(|session| session.id).collect();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.contains(&with_live_token) && sessions.contains(&new));
+        storage.delete_account(user_id, true).await.unwrap();
+    }
+}
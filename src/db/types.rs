@@ -7,6 +7,7 @@ pub struct DbItem {
     pub id: i64,
     pub user_id: Option<i64>,
     pub message_id: Option<i64>,
+    pub deleted_at: Option<chrono::NaiveDateTime>,
 }
 
 #[derive(Debug, Clone, sqlx::FromRow)]
@@ -17,6 +18,83 @@ pub struct DbUser {
     pub first_name: String,
     pub last_name: Option<String>,
     pub created_at: chrono::NaiveDateTime,
+    pub photo_id: Option<i64>,
+    pub bio: Option<String>,
+    pub birthday: Option<chrono::NaiveDate>,
+    pub website: Option<String>,
+    pub status_text: Option<String>,
+    pub status_emoji: Option<String>,
+    pub last_seen_at: Option<chrono::NaiveDateTime>,
+}
+
+/// A user in someone's list, with what the privacy rules need to know about that someone.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbListedUser {
+    pub item_id: i64,
+    pub id: i64,
+    pub email: String,
+    pub username: Option<String>,
+    pub first_name: String,
+    pub last_name: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub photo_id: Option<i64>,
+    pub bio: Option<String>,
+    pub birthday: Option<chrono::NaiveDate>,
+    pub website: Option<String>,
+    pub status_text: Option<String>,
+    pub status_emoji: Option<String>,
+    pub last_seen_at: Option<chrono::NaiveDateTime>,
+    pub contact_first_name: Option<String>,
+    pub contact_last_name: Option<String>,
+    pub has_viewer_as_contact: bool,
+    pub has_blocked_viewer: bool,
+    pub privacy_rules: serde_json::Value,
+    pub listed_at: chrono::NaiveDateTime,
+}
+
+/// A chat of the dialog list: the partner, who may be a deleted account, the last message and the draft.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbDialog {
+    pub item_id: i64,
+    pub id: Option<i64>,
+    pub email: Option<String>,
+    pub username: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub created_at: Option<chrono::NaiveDateTime>,
+    pub photo_id: Option<i64>,
+    pub bio: Option<String>,
+    pub birthday: Option<chrono::NaiveDate>,
+    pub website: Option<String>,
+    pub status_text: Option<String>,
+    pub status_emoji: Option<String>,
+    pub last_seen_at: Option<chrono::NaiveDateTime>,
+    pub contact_first_name: Option<String>,
+    pub contact_last_name: Option<String>,
+    pub has_viewer_as_contact: bool,
+    pub has_blocked_viewer: bool,
+    pub privacy_rules: serde_json::Value,
+    pub message_item_id: Option<i64>,
+    pub message_id: Option<i64>,
+    pub message_from_id: Option<i64>,
+    pub message_chat_id: Option<i64>,
+    pub message_text: Option<String>,
+    pub message_created_at: Option<chrono::NaiveDateTime>,
+    pub message_entities: Option<serde_json::Value>,
+    pub message_link_preview: Option<serde_json::Value>,
+    pub message_action: Option<serde_json::Value>,
+    pub message_expires_at: Option<chrono::NaiveDateTime>,
+    pub message_reply_to_id: Option<i64>,
+    pub message_forward_from_id: Option<i64>,
+    pub message_thread_id: Option<i64>,
+    pub message_reply_count: Option<i32>,
+    pub message_recent_repliers: Option<Vec<i64>>,
+    pub message_views: Option<i64>,
+    pub draft_text: Option<String>,
+    pub draft_entities: Option<serde_json::Value>,
+    pub draft_reply_to_id: Option<i64>,
+    pub draft_updated_at: Option<chrono::NaiveDateTime>,
+    pub unread_mentions_count: i64,
 }
 
 #[derive(Debug, Clone, sqlx::FromRow)]
@@ -26,6 +104,118 @@ pub struct DbMessage {
     pub chat_id: i64,
     pub text: Option<String>,
     pub created_at: chrono::NaiveDateTime,
+    pub entities: serde_json::Value,
+    pub link_preview: Option<serde_json::Value>,
+    pub action: Option<serde_json::Value>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub reply_to_id: Option<i64>,
+    pub forward_from_id: Option<i64>,
+    pub thread_id: Option<i64>,
+    pub reply_count: i32,
+    pub recent_repliers: Vec<i64>,
+    pub views: i64,
+}
+
+/// A message loaded along with the id of its item.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbListedMessage {
+    pub item_id: i64,
+    pub id: i64,
+    pub from_id: i64,
+    pub chat_id: i64,
+    pub text: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub entities: serde_json::Value,
+    pub link_preview: Option<serde_json::Value>,
+    pub action: Option<serde_json::Value>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub reply_to_id: Option<i64>,
+    pub forward_from_id: Option<i64>,
+    pub thread_id: Option<i64>,
+    pub reply_count: i32,
+    pub recent_repliers: Vec<i64>,
+    pub views: i64,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbScheduledMessage {
+    pub id: i64,
+    pub from_id: i64,
+    pub chat_id: i64,
+    pub text: String,
+    pub entities: serde_json::Value,
+    pub schedule_at: chrono::NaiveDateTime,
+    pub created_at: chrono::NaiveDateTime,
+    pub ttl: Option<i32>,
+    pub reply_to_id: Option<i64>,
+    pub thread_id: Option<i64>,
+    pub status: String,
+    pub last_error: Option<String>,
+    pub locked_until: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbPhoto {
+    pub id: i64,
+    pub user_id: i64,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbPassword {
+    pub password_hash: String,
+    pub hint: Option<String>,
+    pub recovery_email: Option<String>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbOutboxEmail {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    pub text_body: Option<String>,
+    pub html_body: Option<String>,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: chrono::NaiveDateTime,
+    pub created_at: chrono::NaiveDateTime,
+    pub sent_at: Option<chrono::NaiveDateTime>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbSession {
+    pub id: i64,
+    pub device_name: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_active_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbPrivacyRule {
+    pub key: String,
+    pub value: String,
+    pub allow_ids: Vec<i64>,
+    pub deny_ids: Vec<i64>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbContact {
+    pub first_name: String,
+    pub last_name: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbDraft {
+    pub chat_id: i64,
+    pub text: String,
+    pub entities: serde_json::Value,
+    pub reply_to_id: Option<i64>,
+    pub updated_at: chrono::NaiveDateTime,
 }
 
 pub enum Item {
@@ -34,17 +224,24 @@ pub enum Item {
 }
 
 #[derive(Debug, Clone)]
+/// Only a salted hash of the code is kept, never the code itself.
+/// `attempt_hash` ties the code to the login attempt that requested it.
 pub struct DbOTP {
-    pub otp: String,
+    pub otp_hash: String,
+    pub salt: String,
+    pub attempt_hash: String,
     pub created_at: u64,
 }
 
 impl FromRedisValue for DbOTP {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
         let v: String = from_redis_value(v)?;
-        if let Some((otp, created_at)) = v.split_once(':') {
+        let mut parts = v.splitn(4, ':');
+        if let (Some(otp_hash), Some(salt), Some(attempt_hash), Some(created_at)) = (parts.next(), parts.next(), parts.next(), parts.next()) {
             Ok(DbOTP {
-                otp: otp.to_string(),
+                otp_hash: otp_hash.to_string(),
+                salt: salt.to_string(),
+                attempt_hash: attempt_hash.to_string(),
                 created_at: created_at.parse().unwrap_or(0),
             })
         } else {
@@ -55,7 +252,7 @@ impl FromRedisValue for DbOTP {
 
 impl Display for DbOTP {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{}", self.otp, self.created_at)
+        write!(f, "{}:{}:{}:{}", self.otp_hash, self.salt, self.attempt_hash, self.created_at)
     }
 }
 
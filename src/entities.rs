@@ -0,0 +1,454 @@
+use serde::{Deserialize, Serialize};
+
+use crate::models::{MessageEntity, MessageEntityKind};
+
+const MAX_ENTITIES: usize = 100;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParseMode {
+    Markdown,
+}
+
+pub fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Returns the part of `text` covered by a UTF-16 range, or `None` if the range
+/// is out of bounds or cuts a character in half.
+pub fn utf16_slice(text: &str, offset: usize, length: usize) -> Option<&str> {
+    let end_position = offset.checked_add(length)?;
+    let mut start = None;
+    let mut end = None;
+    let mut position = 0;
+    for (index, c) in text.char_indices() {
+        if position == offset {
+            start = Some(index);
+        }
+        if position == end_position {
+            end = Some(index);
+        }
+        position += c.len_utf16();
+    }
+    if position == offset {
+        start = Some(text.len());
+    }
+    if position == end_position {
+        end = Some(text.len());
+    }
+    let (start, end) = (start?, end?);
+    if end < start {
+        return None;
+    }
+    Some(&text[start..end])
+}
+
+pub fn sort_entities(entities: &mut [MessageEntity]) {
+    entities.sort_by(|a, b| a.offset.cmp(&b.offset).then(b.length.cmp(&a.length)));
+}
+
+pub fn is_valid_url(url: &str) -> bool {
+    let rest = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"));
+    match rest {
+        Some(rest) => {
+            url.len() <= 2048
+                && !rest.starts_with('/')
+                && !rest.is_empty()
+                && !url.chars().any(|c| c.is_whitespace() || c.is_control())
+        },
+        None => false,
+    }
+}
+
+/// The code points emoji are drawn from, roughly the Extended_Pictographic property.
+/// Regional indicators and skin tone modifiers fall in the 0x1F000 block.
+fn is_pictographic(c: char) -> bool {
+    matches!(c as u32,
+        0x00A9 | 0x00AE | 0x203C | 0x2049 | 0x2122 | 0x2139 | 0x2194..=0x2199 | 0x21A9..=0x21AA
+        | 0x231A..=0x231B | 0x2328 | 0x2388 | 0x23CF | 0x23E9..=0x23F3 | 0x23F8..=0x23FA | 0x24C2
+        | 0x25AA..=0x25AB | 0x25B6 | 0x25C0 | 0x25FB..=0x25FE | 0x2600..=0x27BF | 0x2934..=0x2935
+        | 0x2B05..=0x2B07 | 0x2B1B..=0x2B1C | 0x2B50 | 0x2B55 | 0x3030 | 0x303D | 0x3297 | 0x3299
+        | 0x1F000..=0x1FAFF | 0x1FC00..=0x1FFFD)
+}
+
+/// Checks that a text is made of emoji only: pictographs, flags and keycaps like `1️⃣`,
+/// along with the variation selectors, zero width joiners and tags that build sequences out of them.
+pub fn is_emoji(text: &str) -> bool {
+    let chars: Vec<char> = text.chars().collect();
+    !chars.is_empty() && chars.iter().enumerate().all(|(i, c)| match c {
+        '0'..='9' | '#' | '*' => matches!(chars[i + 1..], ['\u{20E3}', ..] | ['\u{FE0F}', '\u{20E3}', ..]),
+        '\u{FE0F}' | '\u{200D}' | '\u{20E3}' | '\u{E0020}'..='\u{E007F}' => i > 0,
+        c => is_pictographic(*c),
+    })
+}
+
+fn is_username_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn is_valid_language(language: &str) -> bool {
+    !language.is_empty()
+        && language.len() <= 32
+        && language.chars().all(|c| c.is_ascii_alphanumeric() || "+-#_.".contains(c))
+}
+
+/// Checks that entities fit the text, carry valid payloads and either nest or don't touch each other.
+/// Code and pre blocks can't contain other entities.
+pub fn validate_entities(text: &str, entities: &[MessageEntity]) -> bool {
+    if entities.len() > MAX_ENTITIES {
+        return false;
+    }
+    for entity in entities {
+        if entity.length == 0 || entity.offset.checked_add(entity.length).is_none() {
+            return false;
+        }
+        let Some(part) = utf16_slice(text, entity.offset, entity.length) else {
+            return false;
+        };
+        let valid = match &entity.kind {
+            MessageEntityKind::Pre { language: Some(language) } => is_valid_language(language),
+            MessageEntityKind::TextLink { url } => is_valid_url(url),
+            MessageEntityKind::Mention => part
+                .strip_prefix('@')
+                .is_some_and(|username| (3..=20).contains(&username.chars().count()) && username.chars().all(is_username_char)),
+            _ => true,
+        };
+        if !valid {
+            return false;
+        }
+    }
+    let mut sorted = entities.to_vec();
+    sort_entities(&mut sorted);
+    for (index, entity) in sorted.iter().enumerate() {
+        let end = entity.offset + entity.length;
+        for other in &sorted[index + 1..] {
+            if other.offset >= end {
+                break;
+            }
+            if other.offset + other.length > end {
+                return false;
+            }
+            if matches!(entity.kind, MessageEntityKind::Code | MessageEntityKind::Pre { .. }) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn marker_kind(marker: &str) -> MessageEntityKind {
+    match marker {
+        "**" => MessageEntityKind::Bold,
+        "__" => MessageEntityKind::Italic,
+        "~~" => MessageEntityKind::Strikethrough,
+        _ => MessageEntityKind::Spoiler,
+    }
+}
+
+fn starts_with(chars: &[char], pattern: &str) -> bool {
+    let mut chars = chars.iter();
+    pattern.chars().all(|c| chars.next() == Some(&c))
+}
+
+fn find(chars: &[char], from: usize, pattern: &str) -> Option<usize> {
+    (from..chars.len()).find(|&i| starts_with(&chars[i..], pattern))
+}
+
+/// Parses the markdown-like subset clients may send instead of explicit entities:
+/// `**bold**`, `__italic__`, `~~strikethrough~~`, `||spoiler||`, `` `code` ``,
+/// ```` ```language\npre``` ````, `[text](url)` and `@username` mentions.
+/// A backslash escapes the next character and markers that are never closed are kept as
+/// literal text. Returns `None` if an entity would be empty.
+pub fn parse_markdown(source: &str) -> Option<(String, Vec<MessageEntity>)> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut literal = Vec::new();
+    loop {
+        let (text, mut entities, unmatched) = parse_markdown_with(&chars, &literal)?;
+        if unmatched.is_empty() {
+            sort_entities(&mut entities);
+            return Some((text, entities));
+        }
+        // Every pass turns at least one more marker into text, so this ends.
+        literal.extend(unmatched);
+    }
+}
+
+/// One pass of `parse_markdown`: the markers starting at the `literal` indexes are kept as text,
+/// and the indexes of the markers left open are returned alongside the result.
+fn parse_markdown_with(chars: &[char], literal: &[usize]) -> Option<(String, Vec<MessageEntity>, Vec<usize>)> {
+    let mut text = String::new();
+    let mut position = 0;
+    let mut entities = Vec::new();
+    let mut open: Vec<(&str, usize, usize)> = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let rest = &chars[i..];
+        if rest[0] == '\\' && rest.len() > 1 {
+            text.push(rest[1]);
+            position += rest[1].len_utf16();
+            i += 2;
+            continue;
+        }
+        if starts_with(rest, "```") {
+            let mut start = i + 3;
+            let mut language = None;
+            if let Some(line_end) = find(chars, start, "\n") {
+                let line: String = chars[start..line_end].iter().collect();
+                if line.is_empty() || is_valid_language(&line) {
+                    language = Some(line).filter(|line| !line.is_empty());
+                    start = line_end + 1;
+                }
+            }
+            let Some(end) = find(chars, start, "```") else {
+                text.push_str("```");
+                position += 3;
+                i += 3;
+                continue;
+            };
+            let content: String = chars[start..end].iter().collect();
+            let length = utf16_len(&content);
+            if length == 0 {
+                return None;
+            }
+            entities.push(MessageEntity { offset: position, length, kind: MessageEntityKind::Pre { language } });
+            text.push_str(&content);
+            position += length;
+            i = end + 3;
+            continue;
+        }
+        if rest[0] == '`'
+            && let Some(end) = find(chars, i + 1, "`")
+        {
+            let content: String = chars[i + 1..end].iter().collect();
+            let length = utf16_len(&content);
+            if length == 0 {
+                return None;
+            }
+            entities.push(MessageEntity { offset: position, length, kind: MessageEntityKind::Code });
+            text.push_str(&content);
+            position += length;
+            i = end + 1;
+            continue;
+        }
+        if !literal.contains(&i)
+            && let Some(marker) = ["**", "__", "~~", "||"].into_iter().find(|marker| starts_with(rest, marker))
+        {
+            if let Some(index) = open.iter().rposition(|(open_marker, _, _)| *open_marker == marker) {
+                let (_, start, _) = open.remove(index);
+                if start == position {
+                    return None;
+                }
+                entities.push(MessageEntity { offset: start, length: position - start, kind: marker_kind(marker) });
+            } else {
+                open.push((marker, position, i));
+            }
+            i += 2;
+            continue;
+        }
+        if rest[0] == '[' && !literal.contains(&i) {
+            open.push(("[", position, i));
+            i += 1;
+            continue;
+        }
+        if rest[0] == ']'
+            && rest.get(1) == Some(&'(')
+            && let Some(index) = open.iter().rposition(|(open_marker, _, _)| *open_marker == "[")
+            && let Some(end) = find(chars, i + 2, ")")
+        {
+            let url: String = chars[i + 2..end].iter().collect();
+            let (_, start, _) = open.remove(index);
+            if start == position {
+                return None;
+            }
+            entities.push(MessageEntity { offset: start, length: position - start, kind: MessageEntityKind::TextLink { url } });
+            i = end + 1;
+            continue;
+        }
+        if rest[0] == '@' && !text.chars().last().is_some_and(is_username_char) {
+            let mut length = 0;
+            while length + 1 < rest.len() && is_username_char(rest[length + 1]) && !starts_with(&rest[length + 1..], "__") {
+                length += 1;
+            }
+            if (3..=20).contains(&length) {
+                let mention: String = rest[..length + 1].iter().collect();
+                let mention_length = utf16_len(&mention);
+                entities.push(MessageEntity { offset: position, length: mention_length, kind: MessageEntityKind::Mention });
+                text.push_str(&mention);
+                position += mention_length;
+                i += length + 1;
+                continue;
+            }
+        }
+        let length = if literal.contains(&i) && rest[0] != '[' { 2 } else { 1 };
+        for c in &rest[..length] {
+            text.push(*c);
+            position += c.len_utf16();
+        }
+        i += length;
+    }
+    Some((text, entities, open.into_iter().map(|(_, _, index)| index).collect()))
+}
+
+/// Finds the `@username` tokens of a text, skipping the ones inside code and pre blocks.
+pub fn extract_mentions(text: &str, entities: &[MessageEntity]) -> Vec<String> {
+    let code: Vec<(usize, usize)> = entities
+        .iter()
+        .filter(|entity| matches!(entity.kind, MessageEntityKind::Code | MessageEntityKind::Pre { .. }))
+        .map(|entity| (entity.offset, entity.offset.saturating_add(entity.length)))
+        .collect();
+    let chars: Vec<char> = text.chars().collect();
+    let mut mentions = Vec::new();
+    let mut position = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let in_code = code.iter().any(|(start, end)| (*start..*end).contains(&position));
+        if chars[i] == '@' && !in_code && (i == 0 || !is_username_char(chars[i - 1])) {
+            let length = chars[i + 1..].iter().take_while(|c| is_username_char(**c)).count();
+            if (3..=20).contains(&length) {
+                let username: String = chars[i + 1..i + 1 + length].iter().collect();
+                position += 1 + utf16_len(&username);
+                i += 1 + length;
+                if !mentions.contains(&username) {
+                    mentions.push(username);
+                }
+                continue;
+            }
+        }
+        position += chars[i].len_utf16();
+        i += 1;
+    }
+    mentions
+}
+
+/// Returns the first link of a message: the url of a text link or an `http(s)://` address written in the text.
+pub fn find_url(text: &str, entities: &[MessageEntity]) -> Option<String> {
+    let text_link = entities.iter().find_map(|entity| match &entity.kind {
+        MessageEntityKind::TextLink { url } => Some(url.clone()),
+        _ => None,
+    });
+    text_link.or_else(|| {
+        text.split_whitespace()
+            .map(|word| word.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '"', '\'']))
+            .find(|word| is_valid_url(word))
+            .map(|word| word.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(offset: usize, length: usize, kind: MessageEntityKind) -> MessageEntity {
+        MessageEntity { offset, length, kind }
+    }
+
+    #[test]
+    fn utf16_slice_counts_surrogate_pairs() {
+        let text = "a😀b";
+        assert_eq!(utf16_slice(text, 0, 1), Some("a"));
+        assert_eq!(utf16_slice(text, 1, 2), Some("😀"));
+        assert_eq!(utf16_slice(text, 3, 1), Some("b"));
+        assert_eq!(utf16_slice(text, 4, 0), Some(""));
+    }
+
+    #[test]
+    fn utf16_slice_rejects_bad_ranges() {
+        let text = "a😀b";
+        assert_eq!(utf16_slice(text, 2, 1), None);
+        assert_eq!(utf16_slice(text, 1, 1), None);
+        assert_eq!(utf16_slice(text, 3, 5), None);
+        assert_eq!(utf16_slice(text, 1, usize::MAX), None);
+        assert_eq!(utf16_slice(text, usize::MAX, 2), None);
+    }
+
+    #[test]
+    fn validate_entities_accepts_nested_entities() {
+        let entities = [entity(0, 11, MessageEntityKind::Bold), entity(6, 5, MessageEntityKind::Italic)];
+        assert!(validate_entities("hello world", &entities));
+    }
+
+    #[test]
+    fn validate_entities_rejects_overflowing_entities() {
+        assert!(!validate_entities("hello", &[entity(1, usize::MAX, MessageEntityKind::Bold)]));
+        assert!(!validate_entities("hello", &[entity(usize::MAX, 1, MessageEntityKind::Bold)]));
+    }
+
+    #[test]
+    fn validate_entities_rejects_invalid_entities() {
+        assert!(!validate_entities("hello", &[entity(0, 0, MessageEntityKind::Bold)]));
+        assert!(!validate_entities("hello", &[entity(2, 4, MessageEntityKind::Bold)]));
+        let crossing = [entity(0, 3, MessageEntityKind::Bold), entity(2, 3, MessageEntityKind::Italic)];
+        assert!(!validate_entities("hello", &crossing));
+        let inside_code = [entity(0, 5, MessageEntityKind::Code), entity(1, 2, MessageEntityKind::Bold)];
+        assert!(!validate_entities("hello", &inside_code));
+        let bad_url = [entity(0, 5, MessageEntityKind::TextLink { url: "javascript:alert(1)".to_string() })];
+        assert!(!validate_entities("hello", &bad_url));
+    }
+
+    #[test]
+    fn validate_entities_checks_mentions() {
+        assert!(validate_entities("hi @alice", &[entity(3, 6, MessageEntityKind::Mention)]));
+        assert!(!validate_entities("hi alice!", &[entity(3, 6, MessageEntityKind::Mention)]));
+        assert!(validate_entities("hi @jörg", &[entity(3, 5, MessageEntityKind::Mention)]));
+        let (text, entities) = parse_markdown("@élodie").unwrap();
+        assert!(validate_entities(&text, &entities));
+    }
+
+    #[test]
+    fn is_emoji_accepts_sequences_and_keycaps() {
+        for emoji in ["😀", "❤️", "👍🏽", "👨‍👩‍👧", "🇺🇦", "1️⃣", "#⃣", "*️⃣", "©️", "🏴󠁧󠁢󠁳󠁣󠁴󠁿"] {
+            assert!(is_emoji(emoji), "{emoji}");
+        }
+        for text in ["", "a", "1", "#", "😀 ", "é", "\u{FE0F}", "\u{200D}😀", "日本"] {
+            assert!(!is_emoji(text), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn parse_markdown_builds_entities() {
+        let (text, entities) = parse_markdown("**bold** and __it__ `code`").unwrap();
+        assert_eq!(text, "bold and it code");
+        assert_eq!(entities, vec![
+            entity(0, 4, MessageEntityKind::Bold),
+            entity(9, 2, MessageEntityKind::Italic),
+            entity(12, 4, MessageEntityKind::Code),
+        ]);
+    }
+
+    #[test]
+    fn parse_markdown_handles_links_pre_and_escapes() {
+        let (text, entities) = parse_markdown("[site](https://example.com) \\*\\*").unwrap();
+        assert_eq!(text, "site **");
+        assert_eq!(entities, vec![entity(0, 4, MessageEntityKind::TextLink { url: "https://example.com".to_string() })]);
+        let (text, entities) = parse_markdown("```rust\nfn main() {}```").unwrap();
+        assert_eq!(text, "fn main() {}");
+        assert_eq!(entities, vec![entity(0, 12, MessageEntityKind::Pre { language: Some("rust".to_string()) })]);
+    }
+
+    #[test]
+    fn parse_markdown_counts_utf16_and_mentions() {
+        let (text, entities) = parse_markdown("😀 **hi** @alice").unwrap();
+        assert_eq!(text, "😀 hi @alice");
+        assert_eq!(entities, vec![entity(3, 2, MessageEntityKind::Bold), entity(6, 6, MessageEntityKind::Mention)]);
+    }
+
+    #[test]
+    fn parse_markdown_keeps_unmatched_markers_as_text() {
+        assert_eq!(parse_markdown("**bold"), Some(("**bold".to_string(), vec![])));
+        assert_eq!(parse_markdown("[text]"), Some(("[text]".to_string(), vec![])));
+        assert_eq!(parse_markdown("a || b"), Some(("a || b".to_string(), vec![])));
+        assert_eq!(parse_markdown("`code"), Some(("`code".to_string(), vec![])));
+        let (text, entities) = parse_markdown("[**x** y").unwrap();
+        assert_eq!(text, "[x y");
+        assert_eq!(entities, vec![entity(1, 1, MessageEntityKind::Bold)]);
+        let (text, entities) = parse_markdown("**a** **b").unwrap();
+        assert_eq!(text, "a **b");
+        assert_eq!(entities, vec![entity(0, 1, MessageEntityKind::Bold)]);
+    }
+
+    #[test]
+    fn parse_markdown_rejects_empty_entities() {
+        assert_eq!(parse_markdown("``"), None);
+        assert_eq!(parse_markdown("****"), None);
+    }
+}
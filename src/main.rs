@@ -3,6 +3,8 @@ mod models;
 mod services;
 mod api;
 mod random;
+mod entities;
+mod workers;
 
 #[tokio::main]
 async fn main() {
@@ -3,18 +3,236 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: i64,
-    pub email: String,
+    pub email: Option<String>,
     pub username: Option<String>,
     pub first_name: String,
     pub last_name: Option<String>,
+    pub photo_id: Option<i64>,
+    pub bio: Option<String>,
+    /// `YYYY-MM-DD`
+    pub birthday: Option<String>,
+    pub website: Option<String>,
+    pub status_text: Option<String>,
+    pub status_emoji: Option<String>,
+    /// Set for the viewer: whether they have this user in their contacts, and whether it's mutual.
+    pub is_contact: bool,
+    pub is_mutual_contact: bool,
+    pub is_deleted: bool,
     pub created_at: usize,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserStatus {
+    pub user_id: i64,
+    pub online: bool,
+    pub last_seen_at: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivacyKey {
+    LastSeen,
+    Photo,
+    Bio,
+    Email,
+    Messages,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivacyValue {
+    Everybody,
+    Contacts,
+    Nobody,
+}
+
+/// Who may see or do something. Users in `allow_ids` and `deny_ids` are exceptions to `value`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrivacyRule {
+    pub key: PrivacyKey,
+    pub value: PrivacyValue,
+    pub allow_ids: Vec<i64>,
+    pub deny_ids: Vec<i64>,
+}
+
+/// The names are the ones the owner of the contact list chose.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContactName {
+    pub first_name: String,
+    pub last_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contact {
+    pub user: User,
+    pub created_at: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockedUser {
+    pub user: User,
+    pub blocked_at: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Photo {
+    pub id: i64,
+    pub user_id: i64,
+    pub created_at: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailDeliveryStatus {
+    Pending,
+    Sent,
+    Dead,
+    /// The email held a code that ran out before it could be sent.
+    Expired,
+}
+
+/// What support sees about an email in the outbox. The body is never exposed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailDelivery {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    pub status: EmailDeliveryStatus,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: usize,
+    pub created_at: usize,
+    pub sent_at: Option<usize>,
+    pub expires_at: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordInfo {
+    pub has_password: bool,
+    pub hint: Option<String>,
+    pub has_recovery_email: bool,
+    /// A new recovery email that hasn't been confirmed with its code yet.
+    pub pending_recovery_email: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: i64,
+    pub device_name: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: usize,
+    pub last_active_at: usize,
+    pub is_current: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub id: i64,
     pub from_id: i64,
     pub chat_id: i64,
     pub text: Option<String>,
+    pub entities: Vec<MessageEntity>,
+    pub link_preview: Option<LinkPreview>,
+    pub action: Option<MessageAction>,
+    pub reply_to_id: Option<i64>,
+    pub forward_from_id: Option<i64>,
+    pub thread_id: Option<i64>,
+    pub reply_count: i32,
+    pub recent_repliers: Vec<i64>,
+    pub views: i64,
+    pub expires_at: Option<usize>,
     pub created_at: usize,
 }
+
+/// Turns a message into a service message describing a change in the chat.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageAction {
+    SetTimer { ttl: Option<u32> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeletedMessage {
+    pub id: i64,
+    pub from_id: i64,
+    pub chat_id: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+    pub image_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub id: i64,
+    pub from_id: i64,
+    pub chat_id: i64,
+    pub text: String,
+    pub entities: Vec<MessageEntity>,
+    pub reply_to_id: Option<i64>,
+    pub thread_id: Option<i64>,
+    pub ttl: Option<u32>,
+    pub schedule_at: usize,
+    /// Why the message couldn't be delivered. Editing it queues it again.
+    pub error: Option<String>,
+    /// Set while the message is being delivered. It can't be edited or cancelled meanwhile.
+    pub is_sending: bool,
+    pub created_at: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageViews {
+    pub id: i64,
+    pub views: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thread {
+    pub root: Message,
+    pub messages: Vec<Message>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Draft {
+    pub chat_id: i64,
+    pub text: String,
+    pub entities: Vec<MessageEntity>,
+    pub reply_to_id: Option<i64>,
+    pub updated_at: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dialog {
+    pub chat: User,
+    pub last_message: Option<Message>,
+    pub draft: Option<Draft>,
+    pub unread_mentions_count: i64,
+}
+
+/// A formatted span of a message text. `offset` and `length` are measured in UTF-16 code units.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageEntity {
+    pub offset: usize,
+    pub length: usize,
+    #[serde(flatten)]
+    pub kind: MessageEntityKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageEntityKind {
+    Bold,
+    Italic,
+    Code,
+    Pre { language: Option<String> },
+    Strikethrough,
+    Spoiler,
+    TextLink { url: String },
+    Mention,
+}
@@ -1,3 +1,4 @@
+use hmac::{Hmac, Mac};
 use rand::Rng;
 use sha2::Digest;
 
@@ -15,10 +16,27 @@ pub fn random_word(len: usize) -> String {
     (0..len).map(|_| chars[rng.random_range(0..chars.len())]).collect()
 }
 
-pub fn random_hash(value: &str) -> String {
+pub fn sha256(value: &str) -> String {
     let mut hasher = sha2::Sha256::new();
-    hasher.update(random_word(16).as_bytes());
     hasher.update(value.as_bytes());
-    let result = hasher.finalize();
-    hex::encode(result)
+    hex::encode(hasher.finalize())
+}
+
+/// Hashes a one-time code with a per-code salt, keyed by `OTP_SECRET`. Six digits are quick
+/// to brute-force, so a leaked hash must be useless without the server secret.
+pub fn hash_otp(salt: &str, otp: &str) -> String {
+    let secret = std::env::var("OTP_SECRET").expect("OTP_SECRET must be set");
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(salt.as_bytes());
+    mac.update(b":");
+    mac.update(otp.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Compares without returning early, so the time taken doesn't tell how much of a secret matched.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes().zip(b.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
 }
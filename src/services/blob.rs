@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy)]
+pub enum BlobError {
+    InvalidKey,
+    Io,
+}
+
+impl From<std::io::Error> for BlobError {
+    fn from(_: std::io::Error) -> Self {
+        BlobError::Io
+    }
+}
+
+/// Stores uploaded files by key. Keys are relative paths like `photos/1_160.jpg`.
+#[async_trait::async_trait]
+pub trait BlobStore {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), BlobError>;
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobError>;
+    async fn delete(&self, key: &str) -> Result<(), BlobError>;
+}
+
+pub struct FileBlobStore {
+    root: PathBuf,
+}
+
+impl FileBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, BlobError> {
+        let valid = !key.is_empty()
+            && key.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || "/_-.".contains(c));
+        if !valid {
+            return Err(BlobError::InvalidKey);
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for FileBlobStore {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), BlobError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Written under a temporary name first, so readers never see half a file.
+        let temporary_path = path.with_extension("part");
+        tokio::fs::write(&temporary_path, data).await?;
+        tokio::fs::rename(&temporary_path, &path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobError> {
+        let path = self.path(key)?;
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(io_error) if io_error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(io_error) => Err(io_error.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobError> {
+        let path = self.path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(io_error) if io_error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(io_error) => Err(io_error.into()),
+        }
+    }
+}
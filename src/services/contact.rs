@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use crate::{db::{Storage, StorageError}, models::{Contact, ContactName}};
+
+use super::privacy::{ImplPrivacyService, PrivacyService, PrivacyServiceError};
+
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_CONTACTS_PAGE: i64 = 200;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ContactServiceError {
+    Storage(StorageError),
+    Privacy(PrivacyServiceError),
+    InvalidUser,
+    InvalidName,
+    ContactNotFound,
+}
+
+impl From<StorageError> for ContactServiceError {
+    fn from(storage_error: StorageError) -> Self {
+        ContactServiceError::Storage(storage_error)
+    }
+}
+
+impl From<PrivacyServiceError> for ContactServiceError {
+    fn from(privacy_error: PrivacyServiceError) -> Self {
+        ContactServiceError::Privacy(privacy_error)
+    }
+}
+
+#[async_trait::async_trait]
+pub trait ContactService {
+    async fn add_contact(&self, user_id: i64, contact_id: i64, name: &ContactName) -> Result<Contact, ContactServiceError>;
+    async fn rename_contact(&self, user_id: i64, contact_id: i64, name: &ContactName) -> Result<Contact, ContactServiceError>;
+    async fn delete_contact(&self, user_id: i64, contact_id: i64) -> Result<(), ContactServiceError>;
+    async fn get_contacts(&self, user_id: i64, offset: i64, limit: i64) -> Result<Vec<Contact>, ContactServiceError>;
+}
+
+pub struct ImplContactService {
+    storage: Arc<Storage>,
+}
+
+impl ImplContactService {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Self { storage }
+    }
+
+    fn check_name(&self, name: &ContactName) -> Result<ContactName, ContactServiceError> {
+        let first_name = name.first_name.trim().to_string();
+        let last_name = name.last_name.as_deref().map(str::trim).filter(|last_name| !last_name.is_empty()).map(str::to_string);
+        let too_long = first_name.chars().count() > MAX_NAME_LENGTH
+            || last_name.as_ref().is_some_and(|last_name| last_name.chars().count() > MAX_NAME_LENGTH);
+        if first_name.is_empty() || too_long {
+            return Err(ContactServiceError::InvalidName);
+        }
+        Ok(ContactName { first_name, last_name })
+    }
+
+    async fn save_contact(&self, user_id: i64, contact_id: i64, name: &ContactName) -> Result<Contact, ContactServiceError> {
+        let name = self.check_name(name)?;
+        let user = self.storage.get_user(contact_id).await?;
+        let user = user.ok_or(ContactServiceError::InvalidUser)?;
+        let created_at = self.storage.save_contact(user_id, contact_id, &name).await?;
+        let privacy_service = ImplPrivacyService::new(self.storage.clone());
+        let user = privacy_service.filter_user(user_id, user).await?;
+        Ok(Contact { user, created_at })
+    }
+}
+
+#[async_trait::async_trait]
+impl ContactService for ImplContactService {
+    async fn add_contact(&self, user_id: i64, contact_id: i64, name: &ContactName) -> Result<Contact, ContactServiceError> {
+        if user_id == contact_id {
+            return Err(ContactServiceError::InvalidUser);
+        }
+        self.save_contact(user_id, contact_id, name).await
+    }
+
+    async fn rename_contact(&self, user_id: i64, contact_id: i64, name: &ContactName) -> Result<Contact, ContactServiceError> {
+        if !self.storage.is_contact(user_id, contact_id).await? {
+            return Err(ContactServiceError::ContactNotFound);
+        }
+        self.save_contact(user_id, contact_id, name).await
+    }
+
+    async fn delete_contact(&self, user_id: i64, contact_id: i64) -> Result<(), ContactServiceError> {
+        if !self.storage.delete_contact(user_id, contact_id).await? {
+            return Err(ContactServiceError::ContactNotFound);
+        }
+        Ok(())
+    }
+
+    async fn get_contacts(&self, user_id: i64, offset: i64, limit: i64) -> Result<Vec<Contact>, ContactServiceError> {
+        let privacy_service = ImplPrivacyService::new(self.storage.clone());
+        let contacts = self.storage.get_contacts(user_id, offset.max(0), limit.clamp(1, MAX_CONTACTS_PAGE)).await?;
+        let contacts = contacts.into_iter().map(|(viewed_user, created_at)| {
+            Contact { user: privacy_service.filter_viewed_user(user_id, viewed_user), created_at }
+        });
+        Ok(contacts.collect())
+    }
+}
@@ -1,28 +1,301 @@
+use std::{path::PathBuf, sync::Arc, time::SystemTime};
+
+use askama::Template;
+use lettre::{
+    message::{header::ContentType, Mailbox, MultiPart, SinglePart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
 use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{db::{Storage, StorageError}, models::EmailDelivery, random};
+
+const MAX_DELIVERY_ATTEMPTS: i32 = 8;
+const RETRY_DELAY: u64 = 30;
+const MAX_RETRY_DELAY: u64 = 3600;
+const DELIVERY_BATCH: i64 = 20;
+const DELIVERY_LEASE: i64 = 300;
+const MAX_DELIVERIES: i64 = 50;
 
 #[derive(Debug, Clone, Copy)]
 pub enum EmailServiceError {
-    // Internal(String),
+    Storage(StorageError),
+    Template,
+    InvalidAddress,
+    Transport,
+}
+
+impl From<StorageError> for EmailServiceError {
+    fn from(storage_error: StorageError) -> Self {
+        EmailServiceError::Storage(storage_error)
+    }
 }
 
+impl From<askama::Error> for EmailServiceError {
+    fn from(_: askama::Error) -> Self {
+        EmailServiceError::Template
+    }
+}
+
+impl From<lettre::address::AddressError> for EmailServiceError {
+    fn from(_: lettre::address::AddressError) -> Self {
+        EmailServiceError::InvalidAddress
+    }
+}
+
+/// Emails are only queued in the outbox here; `deliver_outbox` sends them in the background.
 #[async_trait::async_trait]
 pub trait EmailService {
-    async fn send_otp(&self, email: &str, otp: &str) -> Result<(), EmailServiceError>;
+    /// The code is only valid for `lifetime` seconds, and so is the email.
+    async fn send_otp(&self, email: &str, otp: &str, lifetime: u64) -> Result<(), EmailServiceError>;
+    async fn send_email_changed(&self, old_email: &str, new_email: &str) -> Result<(), EmailServiceError>;
+    async fn deliver_outbox(&self, transport: &(dyn EmailTransport + Send + Sync)) -> Result<(), EmailServiceError>;
+    async fn get_deliveries(&self, email: &str) -> Result<Vec<EmailDelivery>, EmailServiceError>;
 }
 
-pub struct ImplEmailService {
+/// A rendered email, ready for a transport.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingEmail {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
 }
 
-impl ImplEmailService {
+/// Why a transport couldn't deliver an email. Kept in the outbox for support.
+#[derive(Debug, Clone)]
+pub struct EmailTransportError {
+    pub message: String,
+}
+
+impl EmailTransportError {
+    fn new(message: impl ToString) -> Self {
+        Self { message: message.to_string() }
+    }
+}
+
+/// Delivers rendered emails. Chosen by `EMAIL_BACKEND` at startup.
+#[async_trait::async_trait]
+pub trait EmailTransport {
+    async fn send(&self, email: &OutgoingEmail) -> Result<(), EmailTransportError>;
+}
+
+/// Only logs the emails, for local development.
+pub struct LogEmailTransport {
+}
+
+impl LogEmailTransport {
     pub fn new() -> Self {
         Self {}
     }
 }
 
+#[async_trait::async_trait]
+impl EmailTransport for LogEmailTransport {
+    async fn send(&self, email: &OutgoingEmail) -> Result<(), EmailTransportError> {
+        debug!("Sending email to: {} - {}\n{}", email.to, email.subject, email.text);
+        Ok(())
+    }
+}
+
+/// Writes every email as a JSON file into a directory, so tests can read what was sent.
+pub struct FileEmailTransport {
+    dir: PathBuf,
+}
+
+impl FileEmailTransport {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailTransport for FileEmailTransport {
+    async fn send(&self, email: &OutgoingEmail) -> Result<(), EmailTransportError> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let path = self.dir.join(format!("{}-{}.json", now, random::random_word(8)));
+        let data = serde_json::to_vec_pretty(email).map_err(EmailTransportError::new)?;
+        tokio::fs::create_dir_all(&self.dir).await.map_err(EmailTransportError::new)?;
+        // Written under a temporary name first, so readers never see half a file.
+        let temporary_path = path.with_extension("part");
+        tokio::fs::write(&temporary_path, data).await.map_err(EmailTransportError::new)?;
+        tokio::fs::rename(&temporary_path, &path).await.map_err(EmailTransportError::new)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpTls {
+    /// Plain connection upgraded with STARTTLS, usually port 587.
+    StartTls,
+    /// TLS from the first byte, usually port 465.
+    Implicit,
+}
+
+pub struct SmtpEmailTransport {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpEmailTransport {
+    pub fn new(host: &str, port: u16, tls: SmtpTls, credentials: Option<(String, String)>, from: &str) -> Result<Self, EmailServiceError> {
+        let builder = match tls {
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host),
+            SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(host),
+        };
+        let mut builder = builder.map_err(|_| EmailServiceError::Transport)?.port(port);
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        Ok(Self { transport: builder.build(), from: from.parse()? })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailTransport for SmtpEmailTransport {
+    async fn send(&self, email: &OutgoingEmail) -> Result<(), EmailTransportError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse().map_err(EmailTransportError::new)?)
+            .subject(&email.subject)
+            .multipart(
+                MultiPart::alternative()
+                    .singlepart(SinglePart::builder().header(ContentType::TEXT_PLAIN).body(email.text.clone()))
+                    .singlepart(SinglePart::builder().header(ContentType::TEXT_HTML).body(email.html.clone())),
+            )
+            .map_err(EmailTransportError::new)?;
+        self.transport.send(message).await.map_err(EmailTransportError::new)?;
+        Ok(())
+    }
+}
+
+#[derive(Template)]
+#[template(path = "otp.txt")]
+struct OtpText<'a> {
+    otp: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "otp.html")]
+struct OtpHtml<'a> {
+    otp: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email_changed.txt")]
+struct EmailChangedText<'a> {
+    new_email: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email_changed.html")]
+struct EmailChangedHtml<'a> {
+    new_email: &'a str,
+}
+
+pub struct ImplEmailService {
+    storage: Arc<Storage>,
+}
+
+impl ImplEmailService {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Self { storage }
+    }
+
+    async fn enqueue(&self, email: &OutgoingEmail, expires_in: Option<u64>) -> Result<(), EmailServiceError> {
+        self.storage.enqueue_email(&email.to, &email.subject, &email.text, &email.html, expires_in).await?;
+        Ok(())
+    }
+}
+
 #[async_trait::async_trait]
 impl EmailService for ImplEmailService {
-    async fn send_otp(&self, email: &str, otp: &str) -> Result<(), EmailServiceError> {
-        debug!("Sending OTP to email: {} - {}", email, otp);
+    async fn send_otp(&self, email: &str, otp: &str, lifetime: u64) -> Result<(), EmailServiceError> {
+        let outgoing_email = OutgoingEmail {
+            to: email.to_string(),
+            subject: "Your login code".to_string(),
+            text: OtpText { otp }.render()?,
+            html: OtpHtml { otp }.render()?,
+        };
+        self.enqueue(&outgoing_email, Some(lifetime)).await
+    }
+
+    async fn send_email_changed(&self, old_email: &str, new_email: &str) -> Result<(), EmailServiceError> {
+        let outgoing_email = OutgoingEmail {
+            to: old_email.to_string(),
+            subject: "Your account email was changed".to_string(),
+            text: EmailChangedText { new_email }.render()?,
+            html: EmailChangedHtml { new_email }.render()?,
+        };
+        self.enqueue(&outgoing_email, None).await
+    }
+
+    /// Sends due emails. A failed one is retried with exponential backoff
+    /// and dead-lettered after `MAX_DELIVERY_ATTEMPTS`.
+    async fn deliver_outbox(&self, transport: &(dyn EmailTransport + Send + Sync)) -> Result<(), EmailServiceError> {
+        for db_outbox_email in self.storage.claim_due_emails(DELIVERY_BATCH, DELIVERY_LEASE).await? {
+            let outgoing_email = OutgoingEmail {
+                to: db_outbox_email.recipient,
+                subject: db_outbox_email.subject,
+                text: db_outbox_email.text_body.unwrap_or_default(),
+                html: db_outbox_email.html_body.unwrap_or_default(),
+            };
+            match transport.send(&outgoing_email).await {
+                Ok(()) => self.storage.mark_email_sent(db_outbox_email.id).await?,
+                Err(transport_error) => {
+                    let attempts = db_outbox_email.attempts + 1;
+                    let retry_in = (attempts < MAX_DELIVERY_ATTEMPTS)
+                        .then(|| RETRY_DELAY.saturating_mul(1 << (attempts - 1)).min(MAX_RETRY_DELAY));
+                    log::warn!("Email {} to {} failed: {}", db_outbox_email.id, outgoing_email.to, transport_error.message);
+                    self.storage.mark_email_failed(db_outbox_email.id, &transport_error.message, retry_in).await?;
+                }
+            }
+        }
         Ok(())
     }
+
+    async fn get_deliveries(&self, email: &str) -> Result<Vec<EmailDelivery>, EmailServiceError> {
+        Ok(self.storage.get_email_deliveries(email, MAX_DELIVERIES).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn otp_templates_contain_the_code() {
+        assert!(OtpText { otp: "123456" }.render().unwrap().contains("123456"));
+        assert!(OtpHtml { otp: "123456" }.render().unwrap().contains("123456"));
+    }
+
+    #[test]
+    fn email_changed_html_escapes_the_address() {
+        let html = EmailChangedHtml { new_email: "<b>@example.com" }.render().unwrap();
+        assert!(!html.contains("<b>@example.com"));
+        assert!(html.contains("&#60;b&#62;@example.com"));
+    }
+
+    #[tokio::test]
+    async fn file_transport_writes_whole_emails() {
+        let dir = std::env::temp_dir().join(format!("outbox-{}", random::random_word(8)));
+        let transport = FileEmailTransport::new(&dir);
+        let email = OutgoingEmail {
+            to: "user@example.com".to_string(),
+            subject: "Your login code".to_string(),
+            text: OtpText { otp: "123456" }.render().unwrap(),
+            html: OtpHtml { otp: "123456" }.render().unwrap(),
+        };
+        transport.send(&email).await.unwrap();
+        let paths = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
+        let data = std::fs::read(&paths[0]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].extension().unwrap(), "json");
+        let sent_email: OutgoingEmail = serde_json::from_slice(&data).unwrap();
+        assert_eq!(sent_email.to, email.to);
+        assert_eq!(sent_email.subject, email.subject);
+        assert!(sent_email.text.contains("123456"));
+    }
 }
@@ -1,45 +1,123 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
 
 use log::debug;
 use serde::{Deserialize, Serialize};
-use tokio::{sync::{mpsc::{Receiver, Sender}, RwLock}, time::sleep};
+use tokio::{sync::{mpsc::Sender, Notify, RwLock}, time::sleep};
 use tokio_stream::{wrappers::ReceiverStream, Stream};
 
-use crate::{models::Message, random::random_word};
+use crate::{models::{DeletedMessage, Draft, Message, User, UserStatus}, random::random_word};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BackendEvent {
     Ping,
     MessageSent(Message),
+    Mentioned(Message),
+    MessageEdited(Message),
+    ThreadReply(Message),
+    MessageDeleted(DeletedMessage),
+    DraftUpdated { chat_id: i64, draft: Option<Draft> },
+    UserUpdated(User),
+    UserStatus(UserStatus),
+}
+
+/// Sent by the pool when a user opens their first stream or closes their last one.
+#[derive(Debug, Clone, Copy)]
+pub struct PresenceChange {
+    pub user_id: i64,
+    pub online: bool,
 }
 
 struct Listener {
     id: String,
     user_id: i64,
+    session_id: i64,
     receiver: Sender<BackendEvent>,
+    /// Tells the stream's ping task to stop, so the stream ends once the listener is gone.
+    closed: Arc<Notify>,
 }
 
 pub struct ListenerPool {
     listeners: RwLock<Vec<Listener>>,
+    /// The latest state of every user whose presence changed since the worker last looked.
+    presence_changes: Mutex<HashMap<i64, bool>>,
+    presence_notify: Notify,
 }
 
 impl ListenerPool {
     pub fn new() -> Self {
         Self {
             listeners: RwLock::new(Vec::new()),
+            presence_changes: Mutex::new(HashMap::new()),
+            presence_notify: Notify::new(),
         }
     }
 
-    pub async fn add_listener(&self, user_id: i64, receiver: Sender<BackendEvent>) -> String {
+    pub async fn add_listener(&self, user_id: i64, session_id: i64, receiver: Sender<BackendEvent>, closed: Arc<Notify>) -> String {
         let mut listeners = self.listeners.write().await;
         let id = random_word(32);
-        listeners.push(Listener { id: id.clone(), user_id, receiver });
+        let online = listeners.iter().any(|listener| listener.user_id == user_id);
+        listeners.push(Listener { id: id.clone(), user_id, session_id, receiver, closed });
+        if !online {
+            self.change_presence(user_id, true);
+        }
         id
     }
 
     pub async fn remove_listener(&self, id: String) {
         let mut listeners = self.listeners.write().await;
-        listeners.retain(|listener| listener.id != id);
+        let Some(index) = listeners.iter().position(|listener| listener.id == id) else {
+            return;
+        };
+        let user_id = listeners.remove(index).user_id;
+        if !listeners.iter().any(|listener| listener.user_id == user_id) {
+            self.change_presence(user_id, false);
+        }
+    }
+
+    /// Drops the listeners of terminated sessions, which ends their streams.
+    pub async fn close_sessions(&self, session_ids: &[i64]) {
+        let mut listeners = self.listeners.write().await;
+        let mut user_ids = Vec::new();
+        listeners.retain(|listener| {
+            if !session_ids.contains(&listener.session_id) {
+                return true;
+            }
+            listener.closed.notify_one();
+            user_ids.push(listener.user_id);
+            false
+        });
+        user_ids.sort();
+        user_ids.dedup();
+        for user_id in user_ids {
+            if !listeners.iter().any(|listener| listener.user_id == user_id) {
+                self.change_presence(user_id, false);
+            }
+        }
+    }
+
+    pub async fn is_online(&self, user_id: i64) -> bool {
+        let listeners = self.listeners.read().await;
+        listeners.iter().any(|listener| listener.user_id == user_id)
+    }
+
+    /// Never waits, as it's called under the listeners lock. A newer change of a user replaces
+    /// the one the worker hasn't picked up yet, so the last state always gets through.
+    fn change_presence(&self, user_id: i64, online: bool) {
+        self.presence_changes.lock().unwrap().insert(user_id, online);
+        self.presence_notify.notify_one();
+    }
+
+    /// Waits until some presence changed and takes all the changes.
+    pub async fn take_presence_changes(&self) -> Vec<PresenceChange> {
+        loop {
+            {
+                let mut presence_changes = self.presence_changes.lock().unwrap();
+                if !presence_changes.is_empty() {
+                    return presence_changes.drain().map(|(user_id, online)| PresenceChange { user_id, online }).collect();
+                }
+            }
+            self.presence_notify.notified().await;
+        }
     }
 
     pub async fn notify(&self, user_id: i64, event: BackendEvent) {
@@ -71,15 +149,22 @@ impl EventService {
         Self { listener_pool }
     }
 
-    pub async fn get_user_stream(&self, user_id: i64) -> ReceiverStream<BackendEvent> {
+    /// The stream ends when the client disconnects or when the session is terminated.
+    pub async fn get_user_stream(&self, user_id: i64, session_id: i64) -> ReceiverStream<BackendEvent> {
         let (sender, receiver) = tokio::sync::mpsc::channel(100);
-        self.listener_pool.add_listener(user_id, sender.clone()).await;
+        let closed = Arc::new(Notify::new());
+        let id = self.listener_pool.add_listener(user_id, session_id, sender.clone(), closed.clone()).await;
         let event_service = EventService::new(self.listener_pool.clone());
         tokio::spawn(async move {
+            // The listener goes away as soon as the client disconnects, so presence doesn't wait for the next ping.
             loop {
-                sleep(Duration::from_secs(60)).await;
-                event_service.notify(user_id, BackendEvent::Ping).await;                
+                tokio::select! {
+                    _ = sender.closed() => break,
+                    _ = closed.notified() => break,
+                    _ = sleep(Duration::from_secs(60)) => event_service.notify(user_id, BackendEvent::Ping).await,
+                }
             }
+            event_service.listener_pool.remove_listener(id).await;
         });
         let stream = tokio_stream::wrappers::ReceiverStream::new(receiver);
         stream
@@ -88,4 +173,22 @@ impl EventService {
     pub async fn notify(&self, user_id: i64, event: BackendEvent) {
         self.listener_pool.notify(user_id, event).await;
     }
+
+    pub async fn close_sessions(&self, session_ids: &[i64]) {
+        if !session_ids.is_empty() {
+            self.listener_pool.close_sessions(session_ids).await;
+        }
+    }
+
+    pub async fn is_online(&self, user_id: i64) -> bool {
+        self.listener_pool.is_online(user_id).await
+    }
+
+    /// Sends the event to both sides of a chat, once if the chat is with oneself.
+    pub async fn notify_chat(&self, from_id: i64, chat_id: i64, event: BackendEvent) {
+        self.notify(chat_id, event.clone()).await;
+        if from_id != chat_id {
+            self.notify(from_id, event).await;
+        }
+    }
 }
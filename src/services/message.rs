@@ -1,16 +1,26 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::SystemTime};
 
 use tokio::sync::{mpsc::Receiver, RwLock};
 
-use crate::{db::{Storage, StorageError}, models::Message};
+use crate::{db::{NewMessage, Storage, StorageError}, entities::{self, ParseMode}, models::{Dialog, Draft, Message, MessageAction, MessageEntity, MessageViews, PrivacyKey, ScheduledMessage, Thread}};
 
-use super::events::{BackendEvent, EventService};
+use super::{events::{BackendEvent, EventService}, privacy::{ImplPrivacyService, PrivacyService, PrivacyServiceError}};
 
 #[derive(Debug, Clone, Copy)]
 pub enum MessageServiceError {
     Storage(StorageError),
+    Privacy(PrivacyServiceError),
     InvalidMessage,
+    InvalidEntities,
     InvalidChat,
+    InvalidReply,
+    InvalidThread,
+    InvalidSchedule,
+    InvalidTtl,
+    ScheduledMessageNotFound,
+    ScheduledMessageSending,
+    PrivacyRestricted,
+    Blocked,
 }
 
 impl From<StorageError> for MessageServiceError {
@@ -19,9 +29,20 @@ impl From<StorageError> for MessageServiceError {
     }
 }
 
+impl From<PrivacyServiceError> for MessageServiceError {
+    fn from(privacy_error: PrivacyServiceError) -> Self {
+        MessageServiceError::Privacy(privacy_error)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MessageRequest {
     pub text: String,
+    pub entities: Vec<MessageEntity>,
+    pub parse_mode: Option<ParseMode>,
+    pub reply_to_id: Option<i64>,
+    pub thread_id: Option<i64>,
+    pub ttl: Option<u32>,
 }
 
 #[async_trait::async_trait]
@@ -29,16 +50,219 @@ pub trait MessageService {
     async fn send_message_to_username(&self, from_id: i64, username: &str, message_request: &MessageRequest, event_service: &EventService) -> Result<Message, MessageServiceError>;
     async fn send_message(&self, from_id: i64, chat_id: i64, message_request: &MessageRequest, event_service: &EventService) -> Result<Message, MessageServiceError>;
     async fn auto_send_message(&self, from_id: i64, chat_id: Option<i64>, username: Option<&str>, message_request: &MessageRequest, event_service: &EventService) -> Result<Message, MessageServiceError>;
+    async fn get_unread_mentions(&self, user_id: i64, offset_id: i64, limit: i64) -> Result<(i64, Vec<Message>), MessageServiceError>;
+    async fn read_mentions(&self, user_id: i64, message_ids: Option<&[i64]>) -> Result<i64, MessageServiceError>;
+    async fn schedule_message(&self, from_id: i64, chat_id: Option<i64>, username: Option<&str>, message_request: &MessageRequest, schedule_at: usize, event_service: &EventService) -> Result<ScheduledMessage, MessageServiceError>;
+    async fn get_scheduled_messages(&self, from_id: i64, chat_id: i64) -> Result<Vec<ScheduledMessage>, MessageServiceError>;
+    async fn edit_scheduled_message(&self, from_id: i64, id: i64, message_request: Option<&MessageRequest>, schedule_at: Option<usize>) -> Result<ScheduledMessage, MessageServiceError>;
+    async fn cancel_scheduled_message(&self, from_id: i64, id: i64) -> Result<(), MessageServiceError>;
+    async fn send_scheduled_message(&self, from_id: i64, id: i64, event_service: &EventService) -> Result<Message, MessageServiceError>;
+    async fn send_due_scheduled_messages(&self, event_service: &EventService) -> Result<Vec<Message>, MessageServiceError>;
+    async fn set_chat_ttl(&self, from_id: i64, chat_id: i64, ttl: Option<u32>, event_service: &EventService) -> Result<Message, MessageServiceError>;
+    async fn delete_expired_messages(&self, event_service: &EventService) -> Result<usize, MessageServiceError>;
+    async fn save_draft(&self, user_id: i64, chat_id: i64, text: &str, entities: &[MessageEntity], reply_to_id: Option<i64>, event_service: &EventService) -> Result<Option<Draft>, MessageServiceError>;
+    async fn clear_draft(&self, user_id: i64, chat_id: i64, event_service: &EventService) -> Result<(), MessageServiceError>;
+    async fn get_dialogs(&self, user_id: i64, offset: i64, limit: i64) -> Result<Vec<Dialog>, MessageServiceError>;
+    async fn forward_message(&self, from_id: i64, message_id: i64, chat_id: Option<i64>, event_service: &EventService) -> Result<Message, MessageServiceError>;
+    async fn get_thread(&self, user_id: i64, root_id: i64, offset_id: i64, limit: i64) -> Result<Thread, MessageServiceError>;
+    async fn mark_viewed(&self, user_id: i64, message_ids: &[i64]) -> Result<Vec<MessageViews>, MessageServiceError>;
+    async fn flush_views(&self) -> Result<usize, MessageServiceError>;
 }
 
 pub struct ImplMessageService {
     pub storage: Arc<Storage>,
 }
 
+const MAX_MESSAGE_LENGTH: usize = 4096;
+const MAX_SCHEDULE_DELAY: usize = 365 * 24 * 3600;
+const SCHEDULED_BATCH_SIZE: i64 = 100;
+const SCHEDULED_LEASE: i64 = 60;
+const EXPIRED_BATCH_SIZE: i64 = 500;
+const CHAT_TTLS: [u32; 3] = [24 * 3600, 7 * 24 * 3600, 30 * 24 * 3600];
+const MAX_MESSAGE_TTL: u32 = 30 * 24 * 3600;
+const MAX_THREAD_PAGE: i64 = 100;
+const MAX_DIALOGS_PAGE: i64 = 100;
+const MAX_MENTIONS_PAGE: i64 = 100;
+const MAX_VIEWS_BATCH: usize = 100;
+
+fn now() -> usize {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as usize
+}
+
 impl ImplMessageService {
     pub fn new(storage: Arc<Storage>) -> Self {
         Self { storage }
     }
+
+    fn prepare_text(&self, message_request: &MessageRequest) -> Result<(String, Vec<MessageEntity>), MessageServiceError> {
+        let (text, mut entities) = match message_request.parse_mode {
+            Some(ParseMode::Markdown) => {
+                if !message_request.entities.is_empty() {
+                    return Err(MessageServiceError::InvalidEntities);
+                }
+                entities::parse_markdown(&message_request.text).ok_or(MessageServiceError::InvalidEntities)?
+            },
+            None => (message_request.text.clone(), message_request.entities.clone()),
+        };
+        if text.trim().is_empty() || entities::utf16_len(&text) > MAX_MESSAGE_LENGTH {
+            return Err(MessageServiceError::InvalidMessage);
+        }
+        if message_request.ttl.is_some_and(|ttl| ttl == 0 || ttl > MAX_MESSAGE_TTL) {
+            return Err(MessageServiceError::InvalidTtl);
+        }
+        if !entities::validate_entities(&text, &entities) {
+            return Err(MessageServiceError::InvalidEntities);
+        }
+        entities::sort_entities(&mut entities);
+        Ok((text, entities))
+    }
+
+    /// Messaging yourself is always allowed: that chat is "Saved Messages".
+    async fn can_message(&self, from_id: i64, chat_id: i64) -> Result<bool, MessageServiceError> {
+        Ok(from_id == chat_id || self.storage.is_known(from_id, chat_id).await?)
+    }
+
+    /// The recipient's "messages" privacy rule applies to every delivery, including ones by username
+    /// and scheduled ones, so knowing a username isn't enough to start a chat.
+    async fn check_privacy(&self, from_id: i64, chat_id: i64) -> Result<(), MessageServiceError> {
+        let privacy_service = ImplPrivacyService::new(self.storage.clone());
+        if privacy_service.is_blocked(chat_id, from_id).await? {
+            return Err(MessageServiceError::Blocked);
+        }
+        if !privacy_service.is_allowed(chat_id, from_id, PrivacyKey::Messages).await? {
+            return Err(MessageServiceError::PrivacyRestricted);
+        }
+        Ok(())
+    }
+
+    fn in_chat(&self, message: &Message, user_id: i64, chat_id: i64) -> bool {
+        (message.from_id == user_id && message.chat_id == chat_id) || (message.from_id == chat_id && message.chat_id == user_id)
+    }
+
+    async fn check_reply(&self, from_id: i64, chat_id: i64, reply_to_id: Option<i64>) -> Result<(), MessageServiceError> {
+        if let Some(reply_to_id) = reply_to_id {
+            let message = self.storage.get_message(reply_to_id).await?;
+            if !message.is_some_and(|message| self.in_chat(&message, from_id, chat_id)) {
+                return Err(MessageServiceError::InvalidReply);
+            }
+        }
+        Ok(())
+    }
+
+    /// Threads hang off a regular message of the same chat. Replies inside a thread can't open threads of their own.
+    async fn check_thread(&self, from_id: i64, chat_id: i64, thread_id: Option<i64>) -> Result<(), MessageServiceError> {
+        if let Some(thread_id) = thread_id {
+            let root = self.storage.get_message(thread_id).await?;
+            let valid = root.is_some_and(|root| {
+                self.in_chat(&root, from_id, chat_id) && root.thread_id.is_none() && root.action.is_none()
+            });
+            if !valid {
+                return Err(MessageServiceError::InvalidThread);
+            }
+        }
+        Ok(())
+    }
+
+    async fn deliver(&self, from_id: i64, chat_id: i64, message_request: &MessageRequest, forward_from_id: Option<i64>, event_service: &EventService) -> Result<Message, MessageServiceError> {
+        let (text, entities) = self.prepare_text(message_request)?;
+        self.check_privacy(from_id, chat_id).await?;
+        self.check_reply(from_id, chat_id, message_request.reply_to_id).await?;
+        self.check_thread(from_id, chat_id, message_request.thread_id).await?;
+        let ttl = match message_request.ttl {
+            Some(ttl) => Some(ttl),
+            None => self.storage.get_chat_ttl(from_id, chat_id).await?,
+        };
+        let expires_at = ttl.map(|ttl| now() + ttl as usize);
+        let message = self.storage.create_message(&NewMessage {
+            from_id,
+            chat_id,
+            text: &text,
+            entities: &entities,
+            reply_to_id: message_request.reply_to_id,
+            forward_from_id,
+            thread_id: message_request.thread_id,
+            expires_at,
+        }).await?;
+        self.storage.set_known(from_id, chat_id).await?;
+        if chat_id != from_id {
+            self.storage.set_known(chat_id, from_id).await?;
+        }
+        event_service.notify(chat_id, BackendEvent::MessageSent(message.clone())).await;
+        self.notify_mentions(&message, event_service).await?;
+        if let Some(thread_id) = message.thread_id {
+            self.notify_thread(thread_id, &message, event_service).await?;
+        }
+        Ok(message)
+    }
+
+    fn check_schedule_at(&self, schedule_at: usize) -> Result<(), MessageServiceError> {
+        if schedule_at <= now() || schedule_at > now() + MAX_SCHEDULE_DELAY {
+            return Err(MessageServiceError::InvalidSchedule);
+        }
+        Ok(())
+    }
+
+    async fn get_own_scheduled_message(&self, from_id: i64, id: i64) -> Result<ScheduledMessage, MessageServiceError> {
+        let scheduled_message = self.storage.get_scheduled_message(id).await?;
+        match scheduled_message {
+            Some(scheduled_message) if scheduled_message.from_id == from_id => Ok(scheduled_message),
+            _ => Err(MessageServiceError::ScheduledMessageNotFound),
+        }
+    }
+
+    /// Expects a claimed message and removes it only once it was sent.
+    async fn deliver_scheduled(&self, scheduled_message: ScheduledMessage, event_service: &EventService) -> Result<Message, MessageServiceError> {
+        let id = scheduled_message.id;
+        let message_request = MessageRequest {
+            text: scheduled_message.text,
+            entities: scheduled_message.entities,
+            parse_mode: None,
+            reply_to_id: scheduled_message.reply_to_id,
+            thread_id: scheduled_message.thread_id,
+            ttl: scheduled_message.ttl,
+        };
+        let message = self.deliver(scheduled_message.from_id, scheduled_message.chat_id, &message_request, None, event_service).await?;
+        self.storage.delete_scheduled_message(id).await?;
+        Ok(message)
+    }
+
+    fn can_see_chat(&self, user_id: i64, message: &Message) -> bool {
+        user_id == message.from_id || user_id == message.chat_id
+    }
+
+    async fn notify_mentions(&self, message: &Message, event_service: &EventService) -> Result<(), MessageServiceError> {
+        let text = message.text.as_deref().unwrap_or_default();
+        for username in entities::extract_mentions(text, &message.entities) {
+            let user = self.storage.get_user_by_username(&username).await?;
+            if let Some(user) = user {
+                if user.id == message.from_id || !self.can_see_chat(user.id, message) {
+                    continue;
+                }
+                self.storage.add_mention(user.id, message.id).await?;
+                event_service.notify(user.id, BackendEvent::Mentioned(message.clone())).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates the reply counter of the thread root and tells everyone who took part in the thread,
+    /// except the author of the reply, about it.
+    async fn notify_thread(&self, thread_id: i64, message: &Message, event_service: &EventService) -> Result<(), MessageServiceError> {
+        let Some(root) = self.storage.add_thread_reply(thread_id, message.from_id).await? else {
+            return Ok(());
+        };
+        event_service.notify_chat(root.from_id, root.chat_id, BackendEvent::MessageEdited(root.clone())).await;
+        let mut participants = self.storage.get_thread_participants(thread_id).await?;
+        if !participants.contains(&root.from_id) {
+            participants.push(root.from_id);
+        }
+        for participant in participants {
+            if participant != message.from_id {
+                event_service.notify(participant, BackendEvent::ThreadReply(message.clone())).await;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -46,10 +270,8 @@ impl MessageService for ImplMessageService {
     async fn send_message_to_username(&self, from_id: i64, username: &str, message_request: &MessageRequest, event_service: &EventService) -> Result<Message, MessageServiceError> {
         let user = self.storage.get_user_by_username(username).await?;
         if let Some(user) = user {
-            let message = self.storage.create_message(from_id, user.id, &message_request.text).await?;
-            self.storage.set_known(from_id, user.id).await?;
-            self.storage.set_known(user.id, from_id).await?;
-            event_service.notify(user.id, BackendEvent::MessageSent(message.clone())).await;
+            let message = self.deliver(from_id, user.id, message_request, None, event_service).await?;
+            self.clear_draft(from_id, user.id, event_service).await?;
             Ok(message)
         } else {
             Err(MessageServiceError::InvalidChat)
@@ -57,15 +279,13 @@ impl MessageService for ImplMessageService {
     }
 
     async fn send_message(&self, from_id: i64, chat_id: i64, message_request: &MessageRequest, event_service: &EventService) -> Result<Message, MessageServiceError> {
-        if !self.storage.is_known(from_id, chat_id).await? {
+        if !self.can_message(from_id, chat_id).await? {
             return Err(MessageServiceError::InvalidChat);
         }
         let chat = self.storage.get_user(chat_id).await?;
         if let Some(chat) = chat {
-            let message = self.storage.create_message(from_id, chat.id, &message_request.text).await?;
-            self.storage.set_known(from_id, chat.id).await?;
-            self.storage.set_known(chat.id, from_id).await?;
-            event_service.notify(chat.id, BackendEvent::MessageSent(message.clone())).await;
+            let message = self.deliver(from_id, chat.id, message_request, None, event_service).await?;
+            self.clear_draft(from_id, chat.id, event_service).await?;
             Ok(message)
         } else {
             Err(MessageServiceError::InvalidChat)
@@ -80,4 +300,247 @@ impl MessageService for ImplMessageService {
         }
         Err(MessageServiceError::InvalidChat)
     }
+
+    async fn get_unread_mentions(&self, user_id: i64, offset_id: i64, limit: i64) -> Result<(i64, Vec<Message>), MessageServiceError> {
+        let count = self.storage.count_unread_mentions(user_id).await?;
+        let messages = self.storage.get_unread_mentions(user_id, offset_id.max(0), limit.clamp(1, MAX_MENTIONS_PAGE)).await?;
+        Ok((count, messages))
+    }
+
+    async fn read_mentions(&self, user_id: i64, message_ids: Option<&[i64]>) -> Result<i64, MessageServiceError> {
+        self.storage.read_mentions(user_id, message_ids).await?;
+        Ok(self.storage.count_unread_mentions(user_id).await?)
+    }
+
+    /// Like sending, scheduling uses up the draft of the chat.
+    async fn schedule_message(&self, from_id: i64, chat_id: Option<i64>, username: Option<&str>, message_request: &MessageRequest, schedule_at: usize, event_service: &EventService) -> Result<ScheduledMessage, MessageServiceError> {
+        self.check_schedule_at(schedule_at)?;
+        let chat = if let Some(chat_id) = chat_id {
+            if !self.can_message(from_id, chat_id).await? {
+                return Err(MessageServiceError::InvalidChat);
+            }
+            self.storage.get_user(chat_id).await?
+        } else if let Some(username) = username {
+            self.storage.get_user_by_username(username).await?
+        } else {
+            None
+        };
+        let chat = chat.ok_or(MessageServiceError::InvalidChat)?;
+        let (text, entities) = self.prepare_text(message_request)?;
+        self.check_privacy(from_id, chat.id).await?;
+        self.check_reply(from_id, chat.id, message_request.reply_to_id).await?;
+        self.check_thread(from_id, chat.id, message_request.thread_id).await?;
+        let scheduled_message = ScheduledMessage {
+            id: 0,
+            from_id,
+            chat_id: chat.id,
+            text,
+            entities,
+            reply_to_id: message_request.reply_to_id,
+            thread_id: message_request.thread_id,
+            ttl: message_request.ttl,
+            schedule_at,
+            error: None,
+            is_sending: false,
+            created_at: 0,
+        };
+        let scheduled_message = self.storage.create_scheduled_message(&scheduled_message).await?;
+        self.clear_draft(from_id, chat.id, event_service).await?;
+        Ok(scheduled_message)
+    }
+
+    async fn get_scheduled_messages(&self, from_id: i64, chat_id: i64) -> Result<Vec<ScheduledMessage>, MessageServiceError> {
+        Ok(self.storage.get_scheduled_messages(from_id, chat_id).await?)
+    }
+
+    async fn edit_scheduled_message(&self, from_id: i64, id: i64, message_request: Option<&MessageRequest>, schedule_at: Option<usize>) -> Result<ScheduledMessage, MessageServiceError> {
+        let mut scheduled_message = self.get_own_scheduled_message(from_id, id).await?;
+        if scheduled_message.is_sending {
+            return Err(MessageServiceError::ScheduledMessageSending);
+        }
+        if let Some(message_request) = message_request {
+            let (text, entities) = self.prepare_text(message_request)?;
+            self.check_reply(from_id, scheduled_message.chat_id, message_request.reply_to_id).await?;
+            self.check_thread(from_id, scheduled_message.chat_id, message_request.thread_id).await?;
+            scheduled_message.text = text;
+            scheduled_message.entities = entities;
+            scheduled_message.reply_to_id = message_request.reply_to_id;
+            scheduled_message.thread_id = message_request.thread_id;
+            scheduled_message.ttl = message_request.ttl;
+        }
+        if let Some(schedule_at) = schedule_at {
+            self.check_schedule_at(schedule_at)?;
+            scheduled_message.schedule_at = schedule_at;
+        }
+        // The delivery worker may claim it in the meantime, then nothing is updated.
+        let scheduled_message = self.storage.update_scheduled_message(&scheduled_message).await?;
+        scheduled_message.ok_or(MessageServiceError::ScheduledMessageSending)
+    }
+
+    async fn cancel_scheduled_message(&self, from_id: i64, id: i64) -> Result<(), MessageServiceError> {
+        let scheduled_message = self.get_own_scheduled_message(from_id, id).await?;
+        if scheduled_message.is_sending || self.storage.take_scheduled_message(id).await?.is_none() {
+            return Err(MessageServiceError::ScheduledMessageSending);
+        }
+        Ok(())
+    }
+
+    async fn send_scheduled_message(&self, from_id: i64, id: i64, event_service: &EventService) -> Result<Message, MessageServiceError> {
+        self.get_own_scheduled_message(from_id, id).await?;
+        let scheduled_message = self.storage.claim_scheduled_message(id, SCHEDULED_LEASE).await?;
+        let scheduled_message = scheduled_message.ok_or(MessageServiceError::ScheduledMessageNotFound)?;
+        let result = self.deliver_scheduled(scheduled_message, event_service).await;
+        if result.is_err() {
+            self.storage.release_scheduled_message(id).await?;
+        }
+        result
+    }
+
+    /// Storage errors are retried once the lease runs out. Anything else won't go away by itself,
+    /// e.g. the recipient blocked the sender, so the message is marked failed.
+    async fn send_due_scheduled_messages(&self, event_service: &EventService) -> Result<Vec<Message>, MessageServiceError> {
+        let mut messages = Vec::new();
+        for scheduled_message in self.storage.claim_due_scheduled_messages(SCHEDULED_BATCH_SIZE, SCHEDULED_LEASE).await? {
+            let id = scheduled_message.id;
+            match self.deliver_scheduled(scheduled_message, event_service).await {
+                Ok(message) => messages.push(message),
+                Err(message_error @ (MessageServiceError::Storage(_) | MessageServiceError::Privacy(PrivacyServiceError::Storage(_)))) => {
+                    log::error!("Scheduled message {} wasn't delivered, will retry: {:?}", id, message_error);
+                },
+                Err(message_error) => {
+                    log::warn!("Scheduled message {} can't be delivered: {:?}", id, message_error);
+                    self.storage.mark_scheduled_message_failed(id, &format!("{:?}", message_error)).await?;
+                },
+            }
+        }
+        Ok(messages)
+    }
+
+    async fn set_chat_ttl(&self, from_id: i64, chat_id: i64, ttl: Option<u32>, event_service: &EventService) -> Result<Message, MessageServiceError> {
+        if ttl.is_some_and(|ttl| !CHAT_TTLS.contains(&ttl)) {
+            return Err(MessageServiceError::InvalidTtl);
+        }
+        if !self.can_message(from_id, chat_id).await? || self.storage.get_user(chat_id).await?.is_none() {
+            return Err(MessageServiceError::InvalidChat);
+        }
+        self.storage.set_chat_ttl(from_id, chat_id, ttl).await?;
+        let message = self.storage.create_service_message(from_id, chat_id, &MessageAction::SetTimer { ttl }).await?;
+        event_service.notify_chat(from_id, chat_id, BackendEvent::MessageSent(message.clone())).await;
+        Ok(message)
+    }
+
+    async fn delete_expired_messages(&self, event_service: &EventService) -> Result<usize, MessageServiceError> {
+        let deleted_messages = self.storage.delete_expired_messages(EXPIRED_BATCH_SIZE).await?;
+        for deleted_message in &deleted_messages {
+            event_service.notify_chat(deleted_message.from_id, deleted_message.chat_id, BackendEvent::MessageDeleted(deleted_message.clone())).await;
+        }
+        Ok(deleted_messages.len())
+    }
+
+    async fn save_draft(&self, user_id: i64, chat_id: i64, text: &str, entities: &[MessageEntity], reply_to_id: Option<i64>, event_service: &EventService) -> Result<Option<Draft>, MessageServiceError> {
+        if !self.can_message(user_id, chat_id).await? {
+            return Err(MessageServiceError::InvalidChat);
+        }
+        if text.trim().is_empty() && reply_to_id.is_none() {
+            self.clear_draft(user_id, chat_id, event_service).await?;
+            return Ok(None);
+        }
+        if entities::utf16_len(text) > MAX_MESSAGE_LENGTH {
+            return Err(MessageServiceError::InvalidMessage);
+        }
+        if !entities::validate_entities(text, entities) {
+            return Err(MessageServiceError::InvalidEntities);
+        }
+        self.check_reply(user_id, chat_id, reply_to_id).await?;
+        let mut entities = entities.to_vec();
+        entities::sort_entities(&mut entities);
+        let draft = self.storage.save_draft(user_id, chat_id, text, &entities, reply_to_id).await?;
+        event_service.notify(user_id, BackendEvent::DraftUpdated { chat_id, draft: Some(draft.clone()) }).await;
+        Ok(Some(draft))
+    }
+
+    async fn clear_draft(&self, user_id: i64, chat_id: i64, event_service: &EventService) -> Result<(), MessageServiceError> {
+        if self.storage.delete_draft(user_id, chat_id).await? {
+            event_service.notify(user_id, BackendEvent::DraftUpdated { chat_id, draft: None }).await;
+        }
+        Ok(())
+    }
+
+    async fn get_dialogs(&self, user_id: i64, offset: i64, limit: i64) -> Result<Vec<Dialog>, MessageServiceError> {
+        let privacy_service = ImplPrivacyService::new(self.storage.clone());
+        let dialogs = self.storage.get_dialogs(user_id, offset.max(0), limit.clamp(1, MAX_DIALOGS_PAGE)).await?;
+        Ok(dialogs
+            .into_iter()
+            .map(|dialog| Dialog {
+                chat: privacy_service.filter_viewed_user(user_id, dialog.chat),
+                last_message: dialog.last_message,
+                draft: dialog.draft,
+                unread_mentions_count: dialog.unread_mentions_count,
+            })
+            .collect())
+    }
+
+    async fn forward_message(&self, from_id: i64, message_id: i64, chat_id: Option<i64>, event_service: &EventService) -> Result<Message, MessageServiceError> {
+        let chat_id = chat_id.unwrap_or(from_id);
+        let message = self.storage.get_message(message_id).await?;
+        let message = message
+            .filter(|message| self.can_see_chat(from_id, message))
+            .ok_or(MessageServiceError::InvalidMessage)?;
+        let text = message.text.ok_or(MessageServiceError::InvalidMessage)?;
+        if !self.can_message(from_id, chat_id).await? || self.storage.get_user(chat_id).await?.is_none() {
+            return Err(MessageServiceError::InvalidChat);
+        }
+        let message_request = MessageRequest {
+            text,
+            entities: message.entities,
+            parse_mode: None,
+            reply_to_id: None,
+            thread_id: None,
+            ttl: None,
+        };
+        let forward_from_id = message.forward_from_id.unwrap_or(message.from_id);
+        self.deliver(from_id, chat_id, &message_request, Some(forward_from_id), event_service).await
+    }
+
+    async fn get_thread(&self, user_id: i64, root_id: i64, offset_id: i64, limit: i64) -> Result<Thread, MessageServiceError> {
+        let root = self.storage.get_message(root_id).await?;
+        let root = root
+            .filter(|root| self.can_see_chat(user_id, root) && root.thread_id.is_none())
+            .ok_or(MessageServiceError::InvalidThread)?;
+        let messages = self.storage.get_thread_messages(root_id, offset_id, limit.clamp(1, MAX_THREAD_PAGE)).await?;
+        Ok(Thread { root, messages })
+    }
+
+    /// Counts each user once per message, within the time Redis remembers the viewers of a message.
+    /// Authors don't add views to their own messages, and ids the user can't see are skipped.
+    async fn mark_viewed(&self, user_id: i64, message_ids: &[i64]) -> Result<Vec<MessageViews>, MessageServiceError> {
+        if message_ids.len() > MAX_VIEWS_BATCH {
+            return Err(MessageServiceError::InvalidMessage);
+        }
+        let messages: Vec<Message> = self.storage
+            .get_messages(message_ids)
+            .await?
+            .into_iter()
+            .filter(|message| self.can_see_chat(user_id, message))
+            .collect();
+        if messages.is_empty() {
+            return Ok(Vec::new());
+        }
+        let views: Vec<(i64, bool)> = messages.iter().map(|message| (message.id, message.from_id != user_id)).collect();
+        let pending = self.storage.add_views(user_id, &views).await?;
+        Ok(messages
+            .iter()
+            .zip(pending)
+            .map(|(message, pending)| MessageViews { id: message.id, views: message.views + pending })
+            .collect())
+    }
+
+    async fn flush_views(&self) -> Result<usize, MessageServiceError> {
+        let Some((batch_id, views)) = self.storage.take_pending_views().await? else {
+            return Ok(0);
+        };
+        self.storage.add_message_views(&batch_id, &views).await?;
+        self.storage.finish_pending_views().await?;
+        Ok(views.len())
+    }
 }
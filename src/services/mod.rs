@@ -1,4 +1,11 @@
 pub mod email;
 pub mod user;
 pub mod message;
-pub mod events;
\ No newline at end of file
+pub mod events;
+pub mod preview;
+pub mod blob;
+pub mod photo;
+pub mod presence;
+pub mod privacy;
+pub mod contact;
+pub mod password;
\ No newline at end of file
@@ -0,0 +1,204 @@
+use std::sync::Arc;
+
+use argon2::{password_hash::{rand_core::OsRng, SaltString}, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+
+use crate::{db::{HashedOtp, RecoveryEmailChange, Storage, StorageError}, models::PasswordInfo, random};
+
+use super::email::{EmailService, EmailServiceError};
+
+const MAX_PASSWORD_LENGTH: usize = 256;
+const MAX_HINT_LENGTH: usize = 64;
+const MAX_PASSWORD_ATTEMPTS: i64 = 5;
+const PASSWORD_ATTEMPTS_WINDOW: i64 = 3600;
+const RECOVERY_OTP_LIFETIME: u64 = 600;
+
+#[derive(Debug, Clone, Copy)]
+pub enum PasswordServiceError {
+    Storage(StorageError),
+    Email(EmailServiceError),
+    InvalidPassword,
+    InvalidHint,
+    InvalidEmail,
+    InvalidOTP,
+    OTPNotSent,
+    PasswordNotSet,
+    NoRecoveryEmail,
+    TooManyAttempts,
+    Hashing,
+}
+
+impl From<StorageError> for PasswordServiceError {
+    fn from(storage_error: StorageError) -> Self {
+        PasswordServiceError::Storage(storage_error)
+    }
+}
+
+impl From<EmailServiceError> for PasswordServiceError {
+    fn from(email_error: EmailServiceError) -> Self {
+        PasswordServiceError::Email(email_error)
+    }
+}
+
+#[async_trait::async_trait]
+pub trait PasswordService {
+    async fn get_password_info(&self, user_id: i64) -> Result<PasswordInfo, PasswordServiceError>;
+    async fn set_password<T: EmailService + Send + Sync>(&self, user_id: i64, current_password: Option<&str>, password: &str, hint: Option<&str>, recovery_email: Option<&str>, email_service: &T) -> Result<PasswordInfo, PasswordServiceError>;
+    async fn confirm_recovery_email(&self, user_id: i64, otp: &str) -> Result<PasswordInfo, PasswordServiceError>;
+    async fn remove_password(&self, user_id: i64, current_password: &str) -> Result<(), PasswordServiceError>;
+    async fn check_password(&self, user_id: i64, password: &str) -> Result<(), PasswordServiceError>;
+    async fn send_recovery_otp<T: EmailService + Send + Sync>(&self, user_id: i64, email_service: &T) -> Result<(), PasswordServiceError>;
+    async fn recover_password(&self, user_id: i64, otp: &str) -> Result<(), PasswordServiceError>;
+}
+
+pub struct ImplPasswordService {
+    storage: Arc<Storage>,
+}
+
+impl ImplPasswordService {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Self { storage }
+    }
+
+    /// Argon2 is slow on purpose, so it runs off the async workers.
+    async fn hash_password(password: &str) -> Result<String, PasswordServiceError> {
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|password_hash| password_hash.to_string())
+                .map_err(|_| PasswordServiceError::Hashing)
+        })
+        .await
+        .map_err(|_| PasswordServiceError::Hashing)?
+    }
+
+    async fn verify_password(password: &str, password_hash: &str) -> Result<bool, PasswordServiceError> {
+        let password = password.to_string();
+        let password_hash = password_hash.to_string();
+        tokio::task::spawn_blocking(move || {
+            let password_hash = PasswordHash::new(&password_hash).map_err(|_| PasswordServiceError::Hashing)?;
+            Ok(Argon2::default().verify_password(password.as_bytes(), &password_hash).is_ok())
+        })
+        .await
+        .map_err(|_| PasswordServiceError::Hashing)?
+    }
+
+    /// Password and recovery code guesses share one budget per user.
+    async fn count_attempt(&self, user_id: i64) -> Result<(), PasswordServiceError> {
+        let attempts = self.storage.add_password_attempt(user_id, PASSWORD_ATTEMPTS_WINDOW).await?;
+        if attempts > MAX_PASSWORD_ATTEMPTS {
+            return Err(PasswordServiceError::TooManyAttempts);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl PasswordService for ImplPasswordService {
+    async fn get_password_info(&self, user_id: i64) -> Result<PasswordInfo, PasswordServiceError> {
+        let password = self.storage.get_password(user_id).await?;
+        let recovery_email_change = self.storage.get_recovery_email_change(user_id).await?;
+        Ok(PasswordInfo {
+            has_password: password.is_some(),
+            hint: password.as_ref().and_then(|password| password.hint.clone()),
+            has_recovery_email: password.is_some_and(|password| password.recovery_email.is_some()),
+            pending_recovery_email: recovery_email_change.map(|recovery_email_change| recovery_email_change.email),
+        })
+    }
+
+    /// Changing an existing password needs the current one. A new recovery email only replaces
+    /// the old one once it's confirmed with the code sent to it, see `confirm_recovery_email`.
+    async fn set_password<T: EmailService + Send + Sync>(&self, user_id: i64, current_password: Option<&str>, password: &str, hint: Option<&str>, recovery_email: Option<&str>, email_service: &T) -> Result<PasswordInfo, PasswordServiceError> {
+        if password.is_empty() || password.chars().count() > MAX_PASSWORD_LENGTH {
+            return Err(PasswordServiceError::InvalidPassword);
+        }
+        let hint = hint.map(str::trim).filter(|hint| !hint.is_empty());
+        if hint.is_some_and(|hint| hint.chars().count() > MAX_HINT_LENGTH || hint == password) {
+            return Err(PasswordServiceError::InvalidHint);
+        }
+        let recovery_email = recovery_email.map(str::trim).filter(|recovery_email| !recovery_email.is_empty());
+        if recovery_email.is_some_and(|recovery_email| !recovery_email.contains('@')) {
+            return Err(PasswordServiceError::InvalidEmail);
+        }
+        let stored_password = self.storage.get_password(user_id).await?;
+        if stored_password.is_some() {
+            self.check_password(user_id, current_password.unwrap_or_default()).await?;
+        }
+        let stored_recovery_email = stored_password.and_then(|stored_password| stored_password.recovery_email);
+        let new_recovery_email = recovery_email.filter(|recovery_email| Some(*recovery_email) != stored_recovery_email.as_deref());
+        let kept_recovery_email = if recovery_email.is_some() { stored_recovery_email.as_deref() } else { None };
+        let password_hash = Self::hash_password(password).await?;
+        self.storage.set_password(user_id, &password_hash, hint, kept_recovery_email).await?;
+        if let Some(new_recovery_email) = new_recovery_email {
+            let otp = random::generate_otp();
+            let recovery_email_change = RecoveryEmailChange { email: new_recovery_email.to_string(), otp: HashedOtp::new(&otp) };
+            self.storage.store_recovery_email_change(user_id, &recovery_email_change, RECOVERY_OTP_LIFETIME).await?;
+            email_service.send_otp(new_recovery_email, &otp, RECOVERY_OTP_LIFETIME).await?;
+        } else {
+            self.storage.delete_recovery_email_change(user_id).await?;
+        }
+        self.get_password_info(user_id).await
+    }
+
+    async fn confirm_recovery_email(&self, user_id: i64, otp: &str) -> Result<PasswordInfo, PasswordServiceError> {
+        let recovery_email_change = self.storage.get_recovery_email_change(user_id).await?;
+        let recovery_email_change = recovery_email_change.ok_or(PasswordServiceError::OTPNotSent)?;
+        self.count_attempt(user_id).await?;
+        if !recovery_email_change.otp.matches(otp) {
+            return Err(PasswordServiceError::InvalidOTP);
+        }
+        self.storage.delete_recovery_email_change(user_id).await?;
+        if !self.storage.set_recovery_email(user_id, &recovery_email_change.email).await? {
+            return Err(PasswordServiceError::PasswordNotSet);
+        }
+        self.storage.reset_password_attempts(user_id).await?;
+        self.get_password_info(user_id).await
+    }
+
+    async fn remove_password(&self, user_id: i64, current_password: &str) -> Result<(), PasswordServiceError> {
+        self.check_password(user_id, current_password).await?;
+        self.storage.delete_password(user_id).await?;
+        self.storage.delete_recovery_email_change(user_id).await?;
+        Ok(())
+    }
+
+    async fn check_password(&self, user_id: i64, password: &str) -> Result<(), PasswordServiceError> {
+        let stored_password = self.storage.get_password(user_id).await?;
+        let stored_password = stored_password.ok_or(PasswordServiceError::PasswordNotSet)?;
+        self.count_attempt(user_id).await?;
+        if !Self::verify_password(password, &stored_password.password_hash).await? {
+            return Err(PasswordServiceError::InvalidPassword);
+        }
+        self.storage.reset_password_attempts(user_id).await?;
+        Ok(())
+    }
+
+    async fn send_recovery_otp<T: EmailService + Send + Sync>(&self, user_id: i64, email_service: &T) -> Result<(), PasswordServiceError> {
+        let stored_password = self.storage.get_password(user_id).await?;
+        let stored_password = stored_password.ok_or(PasswordServiceError::PasswordNotSet)?;
+        let recovery_email = stored_password.recovery_email.ok_or(PasswordServiceError::NoRecoveryEmail)?;
+        if self.storage.get_recovery_otp(user_id).await?.is_some() {
+            return Err(PasswordServiceError::OTPNotSent);
+        }
+        let otp = random::generate_otp();
+        self.storage.store_recovery_otp(user_id, &HashedOtp::new(&otp), RECOVERY_OTP_LIFETIME).await?;
+        email_service.send_otp(&recovery_email, &otp, RECOVERY_OTP_LIFETIME).await?;
+        Ok(())
+    }
+
+    /// A code sent to the recovery email removes the password altogether.
+    async fn recover_password(&self, user_id: i64, otp: &str) -> Result<(), PasswordServiceError> {
+        let stored_otp = self.storage.get_recovery_otp(user_id).await?;
+        let stored_otp = stored_otp.ok_or(PasswordServiceError::OTPNotSent)?;
+        self.count_attempt(user_id).await?;
+        if !stored_otp.matches(otp) {
+            return Err(PasswordServiceError::InvalidOTP);
+        }
+        self.storage.delete_recovery_otp(user_id).await?;
+        self.storage.delete_password(user_id).await?;
+        self.storage.delete_recovery_email_change(user_id).await?;
+        self.storage.reset_password_attempts(user_id).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+use std::{io::Cursor, sync::Arc};
+
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, ImageReader, Limits};
+use serde::{Deserialize, Serialize};
+
+use crate::{db::{Storage, StorageError}, models::{Photo, PrivacyKey, User}};
+
+use super::{blob::{BlobError, BlobStore}, events::{BackendEvent, EventService}, privacy::{ImplPrivacyService, PrivacyService, PrivacyServiceError}};
+
+pub const MAX_PHOTO_SIZE: usize = 10 * 1024 * 1024;
+const MAX_PHOTO_DIMENSION: u32 = 8192;
+const PHOTO_QUALITY: u8 = 85;
+
+#[derive(Debug, Clone, Copy)]
+pub enum PhotoServiceError {
+    Storage(StorageError),
+    Blob(BlobError),
+    Privacy(PrivacyServiceError),
+    InvalidImage,
+    PhotoNotFound,
+}
+
+impl From<StorageError> for PhotoServiceError {
+    fn from(storage_error: StorageError) -> Self {
+        PhotoServiceError::Storage(storage_error)
+    }
+}
+
+impl From<PrivacyServiceError> for PhotoServiceError {
+    fn from(privacy_error: PrivacyServiceError) -> Self {
+        PhotoServiceError::Privacy(privacy_error)
+    }
+}
+
+impl From<BlobError> for PhotoServiceError {
+    fn from(blob_error: BlobError) -> Self {
+        PhotoServiceError::Blob(blob_error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PhotoSize {
+    Small,
+    Big,
+}
+
+impl PhotoSize {
+    const ALL: [PhotoSize; 2] = [PhotoSize::Small, PhotoSize::Big];
+
+    fn dimension(&self) -> u32 {
+        match self {
+            PhotoSize::Small => 160,
+            PhotoSize::Big => 640,
+        }
+    }
+
+    fn key(&self, photo_id: i64) -> String {
+        format!("photos/{}_{}.jpg", photo_id, self.dimension())
+    }
+}
+
+#[async_trait::async_trait]
+pub trait PhotoService {
+    async fn upload_photo(&self, user: &User, data: Vec<u8>, event_service: &EventService) -> Result<Photo, PhotoServiceError>;
+    async fn get_photos(&self, viewer_id: i64, user_id: i64) -> Result<Vec<Photo>, PhotoServiceError>;
+    async fn set_photo(&self, user: &User, photo_id: Option<i64>, event_service: &EventService) -> Result<User, PhotoServiceError>;
+    async fn delete_photo(&self, user: &User, photo_id: i64, event_service: &EventService) -> Result<User, PhotoServiceError>;
+    async fn get_photo_file(&self, viewer_id: i64, photo_id: i64, size: PhotoSize) -> Result<Vec<u8>, PhotoServiceError>;
+    async fn delete_photo_files(&self, photo_ids: &[i64]) -> Result<(), PhotoServiceError>;
+}
+
+pub struct ImplPhotoService {
+    storage: Arc<Storage>,
+    blob_store: Arc<dyn BlobStore + Send + Sync>,
+}
+
+impl ImplPhotoService {
+    pub fn new(storage: Arc<Storage>, blob_store: Arc<dyn BlobStore + Send + Sync>) -> Self {
+        Self { storage, blob_store }
+    }
+
+    /// Decodes the upload and cuts the centered square out of it, once per size.
+    fn make_crops(data: &[u8]) -> Result<Vec<(PhotoSize, Vec<u8>)>, PhotoServiceError> {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(MAX_PHOTO_DIMENSION);
+        limits.max_image_height = Some(MAX_PHOTO_DIMENSION);
+        let mut reader = ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .map_err(|_| PhotoServiceError::InvalidImage)?;
+        reader.limits(limits);
+        let image = reader.decode().map_err(|_| PhotoServiceError::InvalidImage)?;
+        let side = image.width().min(image.height());
+        let square = image.crop_imm((image.width() - side) / 2, (image.height() - side) / 2, side, side);
+        let mut crops = Vec::new();
+        for size in PhotoSize::ALL {
+            let crop = square.resize_exact(size.dimension(), size.dimension(), FilterType::Lanczos3).to_rgb8();
+            let mut jpeg = Vec::new();
+            JpegEncoder::new_with_quality(&mut jpeg, PHOTO_QUALITY)
+                .encode_image(&crop)
+                .map_err(|_| PhotoServiceError::InvalidImage)?;
+            crops.push((size, jpeg));
+        }
+        Ok(crops)
+    }
+
+    async fn get_own_photo(&self, user: &User, photo_id: i64) -> Result<Photo, PhotoServiceError> {
+        let photo = self.storage.get_photo(photo_id).await?;
+        photo
+            .filter(|photo| photo.user_id == user.id)
+            .ok_or(PhotoServiceError::PhotoNotFound)
+    }
+
+    /// Sends the updated profile to the user, their chat partners and everyone who has them in contacts,
+    /// as each of them may see it.
+    async fn notify_user_updated(&self, user: &User, event_service: &EventService) -> Result<(), PhotoServiceError> {
+        let privacy_service = ImplPrivacyService::new(self.storage.clone());
+        event_service.notify(user.id, BackendEvent::UserUpdated(user.clone())).await;
+        for item_id in self.storage.get_watchers(user.id).await? {
+            if item_id != user.id && !privacy_service.is_blocked(user.id, item_id).await? {
+                let visible_user = privacy_service.filter_user(item_id, user.clone()).await?;
+                event_service.notify(item_id, BackendEvent::UserUpdated(visible_user)).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn can_see_photos(&self, viewer_id: i64, user_id: i64) -> Result<bool, PhotoServiceError> {
+        let privacy_service = ImplPrivacyService::new(self.storage.clone());
+        Ok(privacy_service.is_allowed(user_id, viewer_id, PrivacyKey::Photo).await?)
+    }
+}
+
+#[async_trait::async_trait]
+impl PhotoService for ImplPhotoService {
+    async fn upload_photo(&self, user: &User, data: Vec<u8>, event_service: &EventService) -> Result<Photo, PhotoServiceError> {
+        if data.len() > MAX_PHOTO_SIZE {
+            return Err(PhotoServiceError::InvalidImage);
+        }
+        let crops = tokio::task::spawn_blocking(move || Self::make_crops(&data))
+            .await
+            .map_err(|_| PhotoServiceError::InvalidImage)??;
+        let photo = self.storage.create_photo(user.id).await?;
+        for (size, jpeg) in &crops {
+            if let Err(blob_error) = self.blob_store.put(&size.key(photo.id), jpeg).await {
+                self.storage.delete_photo(photo.id).await?;
+                return Err(blob_error.into());
+            }
+        }
+        self.set_photo(user, Some(photo.id), event_service).await?;
+        Ok(photo)
+    }
+
+    async fn get_photos(&self, viewer_id: i64, user_id: i64) -> Result<Vec<Photo>, PhotoServiceError> {
+        if !self.can_see_photos(viewer_id, user_id).await? {
+            return Ok(Vec::new());
+        }
+        Ok(self.storage.get_photos(user_id).await?)
+    }
+
+    async fn set_photo(&self, user: &User, photo_id: Option<i64>, event_service: &EventService) -> Result<User, PhotoServiceError> {
+        if let Some(photo_id) = photo_id {
+            self.get_own_photo(user, photo_id).await?;
+        }
+        let user = self.storage.set_user_photo(user.id, photo_id).await?;
+        let user = user.ok_or(PhotoServiceError::PhotoNotFound)?;
+        self.notify_user_updated(&user, event_service).await?;
+        Ok(user)
+    }
+
+    /// Deleting the current photo makes the previous one current again.
+    async fn delete_photo(&self, user: &User, photo_id: i64, event_service: &EventService) -> Result<User, PhotoServiceError> {
+        let photo = self.get_own_photo(user, photo_id).await?;
+        self.storage.delete_photo(photo.id).await?;
+        for size in PhotoSize::ALL {
+            self.blob_store.delete(&size.key(photo.id)).await?;
+        }
+        if user.photo_id != Some(photo.id) {
+            return Ok(user.clone());
+        }
+        let previous_photo = self.storage.get_photos(user.id).await?.first().map(|photo| photo.id);
+        self.set_photo(user, previous_photo, event_service).await
+    }
+
+    async fn get_photo_file(&self, viewer_id: i64, photo_id: i64, size: PhotoSize) -> Result<Vec<u8>, PhotoServiceError> {
+        let photo = self.storage.get_photo(photo_id).await?.ok_or(PhotoServiceError::PhotoNotFound)?;
+        if !self.can_see_photos(viewer_id, photo.user_id).await? {
+            return Err(PhotoServiceError::PhotoNotFound);
+        }
+        let data = self.blob_store.get(&size.key(photo_id)).await?;
+        data.ok_or(PhotoServiceError::PhotoNotFound)
+    }
+
+    /// Removes the files of photos whose rows are already gone, for account deletion.
+    async fn delete_photo_files(&self, photo_ids: &[i64]) -> Result<(), PhotoServiceError> {
+        for photo_id in photo_ids {
+            for size in PhotoSize::ALL {
+                self.blob_store.delete(&size.key(*photo_id)).await?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use crate::{db::{Storage, StorageError}, models::UserStatus};
+
+use super::{events::{BackendEvent, EventService, PresenceChange}, privacy::{ImplPrivacyService, PrivacyService, PrivacyServiceError}};
+
+#[derive(Debug, Clone, Copy)]
+pub enum PresenceServiceError {
+    Storage(StorageError),
+    Privacy(PrivacyServiceError),
+    UserNotFound,
+}
+
+impl From<StorageError> for PresenceServiceError {
+    fn from(storage_error: StorageError) -> Self {
+        PresenceServiceError::Storage(storage_error)
+    }
+}
+
+impl From<PrivacyServiceError> for PresenceServiceError {
+    fn from(privacy_error: PrivacyServiceError) -> Self {
+        PresenceServiceError::Privacy(privacy_error)
+    }
+}
+
+#[async_trait::async_trait]
+pub trait PresenceService {
+    async fn update_presence(&self, presence_change: PresenceChange, event_service: &EventService) -> Result<(), PresenceServiceError>;
+    async fn get_status(&self, viewer_id: i64, user_id: i64, event_service: &EventService) -> Result<UserStatus, PresenceServiceError>;
+}
+
+pub struct ImplPresenceService {
+    storage: Arc<Storage>,
+}
+
+impl ImplPresenceService {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Self { storage }
+    }
+}
+
+#[async_trait::async_trait]
+impl PresenceService for ImplPresenceService {
+    /// Stamps `last_seen_at` on both transitions, so it's accurate as soon as the user goes offline,
+    /// and tells chat partners and everyone who has the user in contacts.
+    async fn update_presence(&self, presence_change: PresenceChange, event_service: &EventService) -> Result<(), PresenceServiceError> {
+        let privacy_service = ImplPrivacyService::new(self.storage.clone());
+        let user_id = presence_change.user_id;
+        self.storage.set_last_seen(user_id).await?;
+        let status = UserStatus {
+            user_id,
+            online: presence_change.online,
+            last_seen_at: self.storage.get_last_seen(user_id).await?,
+        };
+        for item_id in self.storage.get_watchers(user_id).await? {
+            if item_id != user_id && !privacy_service.is_blocked(user_id, item_id).await? {
+                let visible_status = privacy_service.filter_status(item_id, status.clone()).await?;
+                event_service.notify(item_id, BackendEvent::UserStatus(visible_status)).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_status(&self, viewer_id: i64, user_id: i64, event_service: &EventService) -> Result<UserStatus, PresenceServiceError> {
+        if self.storage.get_user(user_id).await?.is_none() {
+            return Err(PresenceServiceError::UserNotFound);
+        }
+        let privacy_service = ImplPrivacyService::new(self.storage.clone());
+        let status = UserStatus {
+            user_id,
+            online: event_service.is_online(user_id).await,
+            last_seen_at: self.storage.get_last_seen(user_id).await?,
+        };
+        Ok(privacy_service.filter_status(viewer_id, status).await?)
+    }
+}
@@ -0,0 +1,365 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::Arc, time::Duration};
+
+use log::{debug, error};
+use reqwest::{redirect::Policy, Url};
+
+use crate::{db::{Storage, StorageError}, entities, models::{LinkPreview, Message}};
+
+use super::events::{BackendEvent, EventService};
+
+const MAX_REDIRECTS: usize = 3;
+const MAX_BODY_SIZE: usize = 512 * 1024;
+const MAX_FIELD_LENGTH: usize = 512;
+/// Pages are only fetched from the standard web ports, so previews can't probe other services.
+const ALLOWED_PORTS: [u16; 2] = [80, 443];
+
+#[derive(Debug, Clone, Copy)]
+pub enum PreviewError {
+    Storage(StorageError),
+    InvalidUrl,
+    ForbiddenAddress,
+    Fetch,
+}
+
+impl From<StorageError> for PreviewError {
+    fn from(storage_error: StorageError) -> Self {
+        PreviewError::Storage(storage_error)
+    }
+}
+
+impl From<reqwest::Error> for PreviewError {
+    fn from(_: reqwest::Error) -> Self {
+        PreviewError::Fetch
+    }
+}
+
+#[async_trait::async_trait]
+pub trait LinkPreviewFetcher {
+    async fn fetch(&self, url: &str) -> Result<Option<LinkPreview>, PreviewError>;
+}
+
+pub struct HttpLinkPreviewFetcher {
+}
+
+impl HttpLinkPreviewFetcher {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    /// Resolves the host of the url and refuses to go on if any of its addresses is not public,
+    /// so previews can't be used to reach the internal network.
+    async fn resolve(&self, url: &Url) -> Result<SocketAddr, PreviewError> {
+        let host = url.host_str().ok_or(PreviewError::InvalidUrl)?;
+        let port = url.port_or_known_default().ok_or(PreviewError::InvalidUrl)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await
+            .map_err(|_| PreviewError::Fetch)?
+            .collect();
+        if addresses.is_empty() || addresses.iter().any(|address| !is_public_ip(address.ip())) {
+            return Err(PreviewError::ForbiddenAddress);
+        }
+        Ok(addresses[0])
+    }
+
+    async fn get_html(&self, url: &str) -> Result<Option<(Url, String)>, PreviewError> {
+        let mut url = Url::parse(url).map_err(|_| PreviewError::InvalidUrl)?;
+        for _ in 0..=MAX_REDIRECTS {
+            if url.scheme() != "http" && url.scheme() != "https" {
+                return Err(PreviewError::InvalidUrl);
+            }
+            if !url.port_or_known_default().is_some_and(|port| ALLOWED_PORTS.contains(&port)) {
+                return Err(PreviewError::ForbiddenAddress);
+            }
+            let address = self.resolve(&url).await?;
+            // The client is pinned to the checked address, so a second DNS answer can't point it elsewhere.
+            let client = reqwest::Client::builder()
+                .redirect(Policy::none())
+                .timeout(Duration::from_secs(5))
+                .user_agent("inogram-preview")
+                .resolve(url.host_str().ok_or(PreviewError::InvalidUrl)?, address)
+                .build()?;
+            let mut response = client.get(url.clone()).send().await?;
+            if response.status().is_redirection() {
+                let location = response.headers().get(reqwest::header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or(PreviewError::Fetch)?;
+                url = url.join(location).map_err(|_| PreviewError::InvalidUrl)?;
+                continue;
+            }
+            if !response.status().is_success() {
+                return Ok(None);
+            }
+            let is_html = response.headers().get(reqwest::header::CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .is_some_and(|content_type| content_type.contains("text/html"));
+            if !is_html {
+                return Ok(None);
+            }
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                body.extend_from_slice(&chunk);
+                if body.len() >= MAX_BODY_SIZE {
+                    body.truncate(MAX_BODY_SIZE);
+                    break;
+                }
+            }
+            return Ok(Some((url, String::from_utf8_lossy(&body).into_owned())));
+        }
+        Err(PreviewError::Fetch)
+    }
+}
+
+#[async_trait::async_trait]
+impl LinkPreviewFetcher for HttpLinkPreviewFetcher {
+    async fn fetch(&self, url: &str) -> Result<Option<LinkPreview>, PreviewError> {
+        let Some((final_url, html)) = self.get_html(url).await? else {
+            return Ok(None);
+        };
+        let meta = parse_meta(&html);
+        let field = |names: &[&str]| {
+            names.iter()
+                .find_map(|name| meta.get(*name))
+                .map(|value| value.trim().chars().take(MAX_FIELD_LENGTH).collect::<String>())
+                .filter(|value| !value.is_empty())
+        };
+        let preview = LinkPreview {
+            url: url.to_string(),
+            title: field(&["og:title", "twitter:title", "title"]),
+            description: field(&["og:description", "twitter:description", "description"]),
+            site_name: field(&["og:site_name"]),
+            image_url: field(&["og:image", "twitter:image"])
+                .and_then(|image| final_url.join(&image).ok())
+                .map(|image| image.to_string())
+                .filter(|image| entities::is_valid_url(image)),
+        };
+        if preview.title.is_none() && preview.description.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(preview))
+    }
+}
+
+/// Serves previews from memory, so message flows can be exercised without network access.
+pub struct FakeLinkPreviewFetcher {
+    previews: HashMap<String, LinkPreview>,
+}
+
+impl FakeLinkPreviewFetcher {
+    pub fn new(previews: HashMap<String, LinkPreview>) -> Self {
+        Self { previews }
+    }
+}
+
+#[async_trait::async_trait]
+impl LinkPreviewFetcher for FakeLinkPreviewFetcher {
+    async fn fetch(&self, url: &str) -> Result<Option<LinkPreview>, PreviewError> {
+        Ok(self.previews.get(url).cloned())
+    }
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || octets[0] == 0
+                || octets[0] >= 240
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+                || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+                || (octets[0] == 198 && (octets[1] & 0xfe) == 18))
+        },
+        IpAddr::V6(ip) => {
+            if ip.is_loopback() || ip.is_unspecified() {
+                return false;
+            }
+            // Mapped and compatible addresses are IPv4 in disguise.
+            if let Some(ip) = ip.to_ipv4() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            // 6to4 and Teredo tunnel to an IPv4 address embedded in the IPv6 one, which may be private.
+            let is_6to4 = segments[0] == 0x2002;
+            let is_teredo = segments[0] == 0x2001 && segments[1] == 0x0000;
+            !(ip.is_multicast()
+                || is_6to4
+                || is_teredo
+                || (segments[0] & 0xfe00) == 0xfc00
+                || (segments[0] & 0xffc0) == 0xfe80
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+                || (segments[0] == 0x0064 && segments[1] == 0xff9b))
+        },
+    }
+}
+
+fn decode_html(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn parse_attributes(tag: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = tag;
+    while let Some(equals) = rest.find('=') {
+        let name = rest[..equals].split_whitespace().last().unwrap_or_default().to_lowercase();
+        let value_part = rest[equals + 1..].trim_start();
+        let (value, next) = match value_part.chars().next() {
+            Some(quote @ ('"' | '\'')) => match value_part[1..].find(quote) {
+                Some(end) => (&value_part[1..end + 1], &value_part[end + 2..]),
+                None => (&value_part[1..], ""),
+            },
+            _ => {
+                let end = value_part.find(char::is_whitespace).unwrap_or(value_part.len());
+                (&value_part[..end], &value_part[end..])
+            },
+        };
+        attributes.insert(name, decode_html(value));
+        rest = next;
+    }
+    attributes
+}
+
+/// Collects `<meta>` properties and the `<title>` of a page. Keys are lowercased.
+fn parse_meta(html: &str) -> HashMap<String, String> {
+    let lowercase = html.to_ascii_lowercase();
+    let mut meta = HashMap::new();
+    let mut position = 0;
+    while let Some(start) = lowercase[position..].find("<meta") {
+        let start = position + start;
+        let Some(end) = lowercase[start..].find('>') else {
+            break;
+        };
+        let attributes = parse_attributes(&html[start + 5..start + end]);
+        let name = attributes.get("property").or_else(|| attributes.get("name"));
+        if let (Some(name), Some(content)) = (name, attributes.get("content")) {
+            meta.entry(name.to_lowercase()).or_insert_with(|| content.clone());
+        }
+        position = start + end;
+    }
+    if let Some(start) = lowercase.find("<title")
+        && let Some(open_end) = lowercase[start..].find('>')
+        && let Some(end) = lowercase[start + open_end..].find("</title>")
+    {
+        let title = &html[start + open_end + 1..start + open_end + end];
+        meta.insert("title".to_string(), decode_html(title));
+    }
+    meta
+}
+
+pub struct PreviewService {
+    storage: Arc<Storage>,
+    fetcher: Arc<dyn LinkPreviewFetcher + Send + Sync>,
+}
+
+impl PreviewService {
+    pub fn new(storage: Arc<Storage>, fetcher: Arc<dyn LinkPreviewFetcher + Send + Sync>) -> Self {
+        Self { storage, fetcher }
+    }
+
+    async fn get_preview(&self, url: &str) -> Result<Option<LinkPreview>, PreviewError> {
+        if let Some(preview) = self.storage.get_cached_preview(url).await? {
+            return Ok(preview);
+        }
+        let preview = match self.fetcher.fetch(url).await {
+            Ok(preview) => preview,
+            Err(PreviewError::Storage(storage_error)) => return Err(PreviewError::Storage(storage_error)),
+            Err(preview_error) => {
+                debug!("Preview of {} is not available: {:?}", url, preview_error);
+                None
+            },
+        };
+        self.storage.cache_preview(url, preview.as_ref()).await?;
+        Ok(preview)
+    }
+
+    /// Looks for the first link of the message and, if the page has a preview,
+    /// stores it with the message and sends the updated message to both sides of the chat.
+    pub async fn attach_preview(&self, message: &Message, event_service: &EventService) -> Result<(), PreviewError> {
+        let text = message.text.as_deref().unwrap_or_default();
+        let Some(url) = entities::find_url(text, &message.entities) else {
+            return Ok(());
+        };
+        let Some(preview) = self.get_preview(&url).await? else {
+            return Ok(());
+        };
+        let message = self.storage.set_link_preview(message.id, &preview).await?;
+        if let Some(message) = message {
+            event_service.notify_chat(message.from_id, message.chat_id, BackendEvent::MessageEdited(message)).await;
+        }
+        Ok(())
+    }
+
+    pub fn spawn_attach_preview(self, message: Message, event_service: EventService) {
+        tokio::spawn(async move {
+            if let Err(preview_error) = self.attach_preview(&message, &event_service).await {
+                error!("Preview Service Error: {:?}", preview_error);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preview(url: &str) -> LinkPreview {
+        LinkPreview {
+            url: url.to_string(),
+            title: Some("Example".to_string()),
+            description: None,
+            site_name: None,
+            image_url: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn fake_fetcher_serves_known_previews() {
+        let fetcher = FakeLinkPreviewFetcher::new(HashMap::from([("https://example.com/".to_string(), preview("https://example.com/"))]));
+        let known = fetcher.fetch("https://example.com/").await.unwrap().unwrap();
+        assert_eq!(known.title.as_deref(), Some("Example"));
+        assert!(fetcher.fetch("https://example.org/").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn http_fetcher_refuses_private_addresses_and_other_ports() {
+        let fetcher = HttpLinkPreviewFetcher::new();
+        for url in ["http://127.0.0.1/", "http://10.0.0.1/", "http://[::1]/", "http://[::ffff:192.168.0.1]/", "http://[2002:c0a8:1::1]/", "http://example.com:8080/", "https://example.com:22/"] {
+            assert!(matches!(fetcher.fetch(url).await, Err(PreviewError::ForbiddenAddress)), "{}", url);
+        }
+        assert!(matches!(fetcher.fetch("ftp://example.com/").await, Err(PreviewError::InvalidUrl)));
+    }
+
+    #[test]
+    fn is_public_ip_rejects_tunnels_and_embedded_private_addresses() {
+        let public = ["8.8.8.8", "2606:4700::1111"];
+        let private = [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+            "::1", "::", "fc00::1", "fe80::1", "::ffff:10.0.0.1", "::10.0.0.1", "64:ff9b::a00:1",
+            "2002:0808:0808::1", "2002:0a00:0001::1", "2001:0000:4136:e378:8000:63bf:3fff:fdd2",
+        ];
+        for ip in public {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in private {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn parse_meta_reads_properties_and_title() {
+        let meta = parse_meta(r#"<html><head><title>Page &amp; co</title><meta property="og:title" content='Open "Graph"'><meta name=description content=Plain></head></html>"#);
+        assert_eq!(meta.get("title").map(String::as_str), Some("Page & co"));
+        assert_eq!(meta.get("og:title").map(String::as_str), Some("Open \"Graph\""));
+        assert_eq!(meta.get("description").map(String::as_str), Some("Plain"));
+    }
+}
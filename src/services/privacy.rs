@@ -0,0 +1,274 @@
+use std::sync::Arc;
+
+use crate::{db::{Storage, StorageError, ViewedUser}, models::{BlockedUser, PrivacyKey, PrivacyRule, PrivacyValue, User, UserStatus}};
+
+const MAX_EXCEPTIONS: usize = 1000;
+const MAX_BLOCKED_PAGE: i64 = 100;
+
+#[derive(Debug, Clone, Copy)]
+pub enum PrivacyServiceError {
+    Storage(StorageError),
+    InvalidRule,
+    InvalidUser,
+}
+
+impl From<StorageError> for PrivacyServiceError {
+    fn from(storage_error: StorageError) -> Self {
+        PrivacyServiceError::Storage(storage_error)
+    }
+}
+
+/// What applies when a user hasn't touched a setting.
+fn default_rule(key: PrivacyKey) -> PrivacyRule {
+    let value = match key {
+        PrivacyKey::Email => PrivacyValue::Nobody,
+        _ => PrivacyValue::Everybody,
+    };
+    PrivacyRule { key, value, allow_ids: Vec::new(), deny_ids: Vec::new() }
+}
+
+/// Every rule, with the defaults for the ones the user hasn't set.
+fn rules_with_defaults(stored_rules: &[PrivacyRule]) -> Vec<PrivacyRule> {
+    let keys = [PrivacyKey::LastSeen, PrivacyKey::Photo, PrivacyKey::Bio, PrivacyKey::Email, PrivacyKey::Messages];
+    let rules = keys.into_iter().map(|key| {
+        stored_rules.iter().find(|rule| rule.key == key).cloned().unwrap_or_else(|| default_rule(key))
+    });
+    rules.collect()
+}
+
+/// Sorts and dedups the exceptions of a rule the owner sets. The owner can't be an exception,
+/// and nobody can be allowed and denied at once.
+fn normalize_rule(owner_id: i64, privacy_rule: &PrivacyRule) -> Result<PrivacyRule, PrivacyServiceError> {
+    let mut privacy_rule = privacy_rule.clone();
+    privacy_rule.allow_ids.sort_unstable();
+    privacy_rule.allow_ids.dedup();
+    privacy_rule.deny_ids.sort_unstable();
+    privacy_rule.deny_ids.dedup();
+    if privacy_rule.allow_ids.len() > MAX_EXCEPTIONS || privacy_rule.deny_ids.len() > MAX_EXCEPTIONS {
+        return Err(PrivacyServiceError::InvalidRule);
+    }
+    if privacy_rule.allow_ids.iter().any(|id| *id == owner_id || privacy_rule.deny_ids.contains(id)) || privacy_rule.deny_ids.contains(&owner_id) {
+        return Err(PrivacyServiceError::InvalidRule);
+    }
+    Ok(privacy_rule)
+}
+
+fn allows(rule: &PrivacyRule, viewer_id: i64, is_contact: bool) -> bool {
+    if rule.deny_ids.contains(&viewer_id) {
+        return false;
+    }
+    if rule.allow_ids.contains(&viewer_id) {
+        return true;
+    }
+    match rule.value {
+        PrivacyValue::Everybody => true,
+        PrivacyValue::Contacts => is_contact,
+        PrivacyValue::Nobody => false,
+    }
+}
+
+/// A blocked viewer sees only what the owner shares with everybody, like any stranger would,
+/// so the block doesn't show. Reaching the owner is never allowed.
+/// There are no groups yet, so keeping a blocked user from adding the owner to one isn't covered here.
+fn allows_blocked(rule: &PrivacyRule) -> bool {
+    match rule.key {
+        PrivacyKey::Messages => false,
+        PrivacyKey::LastSeen | PrivacyKey::Photo | PrivacyKey::Bio | PrivacyKey::Email => rule.value == PrivacyValue::Everybody,
+    }
+}
+
+#[async_trait::async_trait]
+pub trait PrivacyService {
+    async fn get_rules(&self, user_id: i64) -> Result<Vec<PrivacyRule>, PrivacyServiceError>;
+    async fn set_rule(&self, user_id: i64, privacy_rule: &PrivacyRule) -> Result<PrivacyRule, PrivacyServiceError>;
+    async fn is_allowed(&self, owner_id: i64, viewer_id: i64, key: PrivacyKey) -> Result<bool, PrivacyServiceError>;
+    async fn filter_user(&self, viewer_id: i64, user: User) -> Result<User, PrivacyServiceError>;
+    fn filter_viewed_user(&self, viewer_id: i64, viewed_user: ViewedUser) -> User;
+    async fn filter_status(&self, viewer_id: i64, status: UserStatus) -> Result<UserStatus, PrivacyServiceError>;
+    async fn is_blocked(&self, owner_id: i64, viewer_id: i64) -> Result<bool, PrivacyServiceError>;
+    async fn block_user(&self, user_id: i64, blocked_id: i64) -> Result<(), PrivacyServiceError>;
+    async fn unblock_user(&self, user_id: i64, blocked_id: i64) -> Result<(), PrivacyServiceError>;
+    async fn get_blocked_users(&self, user_id: i64, offset: i64, limit: i64) -> Result<Vec<BlockedUser>, PrivacyServiceError>;
+}
+
+pub struct ImplPrivacyService {
+    storage: Arc<Storage>,
+}
+
+impl ImplPrivacyService {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Self { storage }
+    }
+
+    async fn get_rule(&self, user_id: i64, key: PrivacyKey) -> Result<PrivacyRule, PrivacyServiceError> {
+        let rules = self.storage.get_privacy_rules(user_id).await?;
+        Ok(rules.into_iter().find(|rule| rule.key == key).unwrap_or_else(|| default_rule(key)))
+    }
+
+    /// The owner decides who counts as a contact, so it's the owner's contact list that is checked.
+    async fn is_contact(&self, owner_id: i64, viewer_id: i64) -> Result<bool, PrivacyServiceError> {
+        Ok(self.storage.is_contact(owner_id, viewer_id).await?)
+    }
+}
+
+#[async_trait::async_trait]
+impl PrivacyService for ImplPrivacyService {
+    async fn get_rules(&self, user_id: i64) -> Result<Vec<PrivacyRule>, PrivacyServiceError> {
+        let stored_rules = self.storage.get_privacy_rules(user_id).await?;
+        Ok(rules_with_defaults(&stored_rules))
+    }
+
+    async fn set_rule(&self, user_id: i64, privacy_rule: &PrivacyRule) -> Result<PrivacyRule, PrivacyServiceError> {
+        let privacy_rule = normalize_rule(user_id, privacy_rule)?;
+        Ok(self.storage.set_privacy_rule(user_id, &privacy_rule).await?)
+    }
+
+    async fn is_allowed(&self, owner_id: i64, viewer_id: i64, key: PrivacyKey) -> Result<bool, PrivacyServiceError> {
+        if owner_id == viewer_id {
+            return Ok(true);
+        }
+        let rule = self.get_rule(owner_id, key).await?;
+        if self.is_blocked(owner_id, viewer_id).await? {
+            return Ok(allows_blocked(&rule));
+        }
+        let is_contact = rule.value == PrivacyValue::Contacts && self.is_contact(owner_id, viewer_id).await?;
+        Ok(allows(&rule, viewer_id, is_contact))
+    }
+
+    /// Strips whatever the owner doesn't share with the viewer and shows the name the viewer saved the contact under.
+    /// Must be called before a `User` leaves for someone else.
+    async fn filter_user(&self, viewer_id: i64, user: User) -> Result<User, PrivacyServiceError> {
+        if user.id == viewer_id {
+            return Ok(user);
+        }
+        let viewed_user = ViewedUser {
+            contact_name: self.storage.get_contact_name(viewer_id, user.id).await?,
+            has_viewer_as_contact: self.is_contact(user.id, viewer_id).await?,
+            has_blocked_viewer: self.is_blocked(user.id, viewer_id).await?,
+            privacy_rules: self.storage.get_privacy_rules(user.id).await?,
+            user,
+        };
+        Ok(self.filter_viewed_user(viewer_id, viewed_user))
+    }
+
+    /// Same as `filter_user`, for lists that load everything it needs along with the users.
+    fn filter_viewed_user(&self, viewer_id: i64, viewed_user: ViewedUser) -> User {
+        let mut user = viewed_user.user;
+        if user.id == viewer_id {
+            return user;
+        }
+        if let Some(contact_name) = viewed_user.contact_name {
+            user.first_name = contact_name.first_name;
+            user.last_name = contact_name.last_name;
+            user.is_contact = true;
+        }
+        user.is_mutual_contact = user.is_contact && viewed_user.has_viewer_as_contact;
+        for rule in rules_with_defaults(&viewed_user.privacy_rules) {
+            let allowed = if viewed_user.has_blocked_viewer {
+                allows_blocked(&rule)
+            } else {
+                allows(&rule, viewer_id, viewed_user.has_viewer_as_contact)
+            };
+            if allowed {
+                continue;
+            }
+            match rule.key {
+                PrivacyKey::Photo => user.photo_id = None,
+                PrivacyKey::Bio => user.bio = None,
+                PrivacyKey::Email => user.email = None,
+                PrivacyKey::LastSeen | PrivacyKey::Messages => (),
+            }
+        }
+        user
+    }
+
+    async fn filter_status(&self, viewer_id: i64, mut status: UserStatus) -> Result<UserStatus, PrivacyServiceError> {
+        if !self.is_allowed(status.user_id, viewer_id, PrivacyKey::LastSeen).await? {
+            status.online = false;
+            status.last_seen_at = None;
+        }
+        Ok(status)
+    }
+
+    async fn is_blocked(&self, owner_id: i64, viewer_id: i64) -> Result<bool, PrivacyServiceError> {
+        Ok(self.storage.is_blocked(owner_id, viewer_id).await?)
+    }
+
+    async fn block_user(&self, user_id: i64, blocked_id: i64) -> Result<(), PrivacyServiceError> {
+        if user_id == blocked_id || self.storage.get_user(blocked_id).await?.is_none() {
+            return Err(PrivacyServiceError::InvalidUser);
+        }
+        Ok(self.storage.block_user(user_id, blocked_id).await?)
+    }
+
+    async fn unblock_user(&self, user_id: i64, blocked_id: i64) -> Result<(), PrivacyServiceError> {
+        self.storage.unblock_user(user_id, blocked_id).await?;
+        Ok(())
+    }
+
+    async fn get_blocked_users(&self, user_id: i64, offset: i64, limit: i64) -> Result<Vec<BlockedUser>, PrivacyServiceError> {
+        let blocked_users = self.storage.get_blocked_users(user_id, offset.max(0), limit.clamp(1, MAX_BLOCKED_PAGE)).await?;
+        let blocked_users = blocked_users.into_iter().map(|(viewed_user, blocked_at)| {
+            BlockedUser { user: self.filter_viewed_user(user_id, viewed_user), blocked_at }
+        });
+        Ok(blocked_users.collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(key: PrivacyKey, value: PrivacyValue, allow_ids: Vec<i64>, deny_ids: Vec<i64>) -> PrivacyRule {
+        PrivacyRule { key, value, allow_ids, deny_ids }
+    }
+
+    #[test]
+    fn allows_applies_exceptions_before_the_value() {
+        let contacts = rule(PrivacyKey::Photo, PrivacyValue::Contacts, vec![2], vec![3]);
+        assert!(allows(&contacts, 2, false));
+        assert!(!allows(&contacts, 3, true));
+        assert!(allows(&contacts, 4, true));
+        assert!(!allows(&contacts, 4, false));
+        assert!(allows(&rule(PrivacyKey::Bio, PrivacyValue::Everybody, vec![], vec![]), 4, false));
+        assert!(!allows(&rule(PrivacyKey::Bio, PrivacyValue::Nobody, vec![], vec![]), 4, true));
+        assert!(allows(&rule(PrivacyKey::Bio, PrivacyValue::Nobody, vec![4], vec![]), 4, false));
+    }
+
+    #[test]
+    fn allows_blocked_shows_only_what_everybody_sees() {
+        assert!(allows_blocked(&rule(PrivacyKey::Photo, PrivacyValue::Everybody, vec![], vec![])));
+        assert!(!allows_blocked(&rule(PrivacyKey::Photo, PrivacyValue::Contacts, vec![], vec![])));
+        assert!(!allows_blocked(&rule(PrivacyKey::LastSeen, PrivacyValue::Nobody, vec![2], vec![])));
+        assert!(!allows_blocked(&rule(PrivacyKey::Messages, PrivacyValue::Everybody, vec![], vec![])));
+    }
+
+    #[test]
+    fn rules_with_defaults_keeps_stored_rules() {
+        let rules = rules_with_defaults(&[rule(PrivacyKey::Email, PrivacyValue::Contacts, vec![], vec![])]);
+        assert_eq!(rules.len(), 5);
+        let email = rules.iter().find(|rule| rule.key == PrivacyKey::Email).unwrap();
+        assert_eq!(email.value, PrivacyValue::Contacts);
+        let bio = rules.iter().find(|rule| rule.key == PrivacyKey::Bio).unwrap();
+        assert_eq!(bio.value, PrivacyValue::Everybody);
+    }
+
+    #[test]
+    fn normalize_rule_sorts_and_dedups_exceptions() {
+        let privacy_rule = normalize_rule(1, &rule(PrivacyKey::Bio, PrivacyValue::Contacts, vec![5, 2, 5], vec![9, 7, 9])).unwrap();
+        assert_eq!(privacy_rule.allow_ids, vec![2, 5]);
+        assert_eq!(privacy_rule.deny_ids, vec![7, 9]);
+    }
+
+    #[test]
+    fn normalize_rule_rejects_invalid_exceptions() {
+        let owner_allowed = rule(PrivacyKey::Bio, PrivacyValue::Nobody, vec![1], vec![]);
+        assert!(matches!(normalize_rule(1, &owner_allowed), Err(PrivacyServiceError::InvalidRule)));
+        let owner_denied = rule(PrivacyKey::Bio, PrivacyValue::Everybody, vec![], vec![1]);
+        assert!(matches!(normalize_rule(1, &owner_denied), Err(PrivacyServiceError::InvalidRule)));
+        let overlapping = rule(PrivacyKey::Bio, PrivacyValue::Contacts, vec![2, 3], vec![3]);
+        assert!(matches!(normalize_rule(1, &overlapping), Err(PrivacyServiceError::InvalidRule)));
+        let too_many = rule(PrivacyKey::Bio, PrivacyValue::Contacts, (2..MAX_EXCEPTIONS as i64 + 3).collect(), vec![]);
+        assert!(matches!(normalize_rule(1, &too_many), Err(PrivacyServiceError::InvalidRule)));
+    }
+}
@@ -1,23 +1,31 @@
 use std::{pin::Pin, sync::Arc, time::{Duration, SystemTime}};
 
+use chrono::Datelike;
 use jsonwebtoken::EncodingKey;
 use serde::{Deserialize, Serialize};
 
-use crate::{db::{Storage, StorageError}, models::User, random};
+use crate::{db::{EmailChange, HashedOtp, NewSession, PendingLogin, Storage, StorageError}, entities, models::{Session, User}, random};
 
-use super::email::{EmailService, EmailServiceError};
+use super::{email::{EmailService, EmailServiceError}, events::{BackendEvent, EventService}, password::{ImplPasswordService, PasswordService, PasswordServiceError}, photo::{PhotoService, PhotoServiceError}};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum UserServiceError {
     Storage(StorageError),
     Email(EmailServiceError),
+    Photo(PhotoServiceError),
+    Password(PasswordServiceError),
     InvalidEmail,
+    EmailUsed,
     InvalidOTP,
     OTPNotSent,
+    TooManyAttempts,
     InvalidAuthentication,
     UserAlreadyExists,
     UsernameUsed,
     InvalidUsername,
+    UnknownField(String),
+    InvalidField(String),
+    SessionNotFound,
 }
 
 impl From<StorageError> for UserServiceError {
@@ -26,6 +34,18 @@ impl From<StorageError> for UserServiceError {
     }
 }
 
+impl From<PhotoServiceError> for UserServiceError {
+    fn from(photo_error: PhotoServiceError) -> Self {
+        UserServiceError::Photo(photo_error)
+    }
+}
+
+impl From<PasswordServiceError> for UserServiceError {
+    fn from(password_error: PasswordServiceError) -> Self {
+        UserServiceError::Password(password_error)
+    }
+}
+
 impl From<EmailServiceError> for UserServiceError {
     fn from(email_error: EmailServiceError) -> Self {
         UserServiceError::Email(email_error)
@@ -34,8 +54,13 @@ impl From<EmailServiceError> for UserServiceError {
 
 #[async_trait::async_trait]
 pub trait UserService {
-    async fn send_otp<T: EmailService + Send + Sync>(&self, email: &str, email_service: &T) -> Result<String, UserServiceError>;
-    async fn verify_otp(&self, email: &str, otp: &str) -> Result<String, UserServiceError>;
+    async fn send_otp<T: EmailService + Send + Sync>(&self, email: &str, email_service: &T) -> Result<OtpStatus, UserServiceError>;
+    async fn resend_otp<T: EmailService + Send + Sync>(&self, email: &str, email_service: &T) -> Result<OtpStatus, UserServiceError>;
+    async fn verify_otp(&self, email: &str, otp: &str, attempt_id: &str, new_session: &NewSession<'_>) -> Result<Login, UserServiceError>;
+    async fn verify_password(&self, password_token: &str, password: &str) -> Result<Tokens, UserServiceError>;
+    async fn send_password_recovery<T: EmailService + Send + Sync>(&self, password_token: &str, email_service: &T) -> Result<(), UserServiceError>;
+    async fn recover_password(&self, password_token: &str, otp: &str) -> Result<Tokens, UserServiceError>;
+    async fn refresh_tokens(&self, refresh_token: &str, event_service: &EventService) -> Result<Tokens, UserServiceError>;
     async fn authenticate(&self, token: &str) -> Result<Option<User>, UserServiceError>;
     async fn authenticate_with_user(&self, token: &str) -> Result<User, UserServiceError> {
         if let Some(user) = self.authenticate(token).await? {
@@ -43,18 +68,80 @@ pub trait UserService {
         }
         Err(UserServiceError::InvalidAuthentication)
     }
-    async fn create_user(&self, token: &str, first_name: &str, last_name: Option<&str>) -> Result<User, UserServiceError>;
+    /// Returns the user along with the id of the session the token belongs to.
+    async fn authenticate_session(&self, token: &str) -> Result<(User, i64), UserServiceError>;
+    async fn create_user(&self, signup_token: &str, first_name: &str, last_name: Option<&str>) -> Result<(User, Tokens), UserServiceError>;
     async fn patch_me(&self, token: &str, fields: Vec<PatchUserField>) -> Result<User, UserServiceError>;
+    async fn delete_me<T: PhotoService + Send + Sync>(&self, token: &str, otp: &str, attempt_id: &str, delete_messages: bool, photo_service: &T, event_service: &EventService) -> Result<(), UserServiceError>;
+    async fn request_email_change<T: EmailService + Send + Sync>(&self, token: &str, new_email: &str, email_service: &T) -> Result<OtpStatus, UserServiceError>;
+    async fn confirm_email_change<T: EmailService + Send + Sync>(&self, token: &str, old_otp: &str, new_otp: &str, email_service: &T, event_service: &EventService) -> Result<User, UserServiceError>;
+    async fn get_sessions(&self, token: &str) -> Result<Vec<Session>, UserServiceError>;
+    async fn terminate_session(&self, token: &str, session_id: i64, event_service: &EventService) -> Result<(), UserServiceError>;
+    async fn terminate_other_sessions(&self, token: &str, event_service: &EventService) -> Result<(), UserServiceError>;
+    /// Returns how many sessions were pruned.
+    async fn delete_expired_sessions(&self, event_service: &EventService) -> Result<usize, UserServiceError>;
 }
 
 pub struct ImplUserService {
     storage: Arc<Storage>,
 }
 
+const TOKEN_LIFETIME: u64 = 15 * 60;
+const REFRESH_TOKEN_LIFETIME: u64 = 30 * 24 * 3600;
+const NEW_SESSION_GRACE: i64 = 3600;
+const OTP_LIFETIME: u64 = 300;
+const SIGNUP_LIFETIME: u64 = 3600;
+const EMAIL_CHANGE_LIFETIME: u64 = 600;
+const OTP_COOLDOWN: u64 = 30;
+const MAX_OTP_COOLDOWN: u64 = 240;
+const OTP_SENDS_WINDOW: i64 = 3600;
+const MAX_OTP_SENDS: i64 = 10;
+const MAX_OTP_ATTEMPTS: i64 = 5;
+const MAX_OTP_FAILURES_PER_EMAIL: i64 = 20;
+const MAX_OTP_FAILURES_PER_IP: i64 = 100;
+const OTP_FAILURES_WINDOW: i64 = 3600;
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_BIO_LENGTH: usize = 140;
+const MAX_WEBSITE_LENGTH: usize = 256;
+const MAX_STATUS_TEXT_LENGTH: usize = 70;
+const MAX_STATUS_EMOJI_LENGTH: usize = 8;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct JwtClaims {
     pub sub: String,
     pub exp: usize,
+    pub iat: usize,
+    pub sid: i64,
+}
+
+/// The user and session a valid access token belongs to.
+struct TokenOwner {
+    user_id: i64,
+    session_id: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tokens {
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+/// Where the email OTP stands. `attempt_id` is only set when a new code was just sent,
+/// and the code can only be redeemed together with it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OtpStatus {
+    pub attempt_id: Option<String>,
+    pub retry_after: u64,
+    pub expires_in: u64,
+}
+
+/// What passing the email OTP gives: tokens, a token for the password step when two-step verification is on,
+/// or a token to create the account with when there's none for the email yet.
+#[derive(Debug, Clone)]
+pub enum Login {
+    Tokens(Tokens),
+    PasswordRequired { password_token: String, hint: Option<String> },
+    SignupRequired { signup_token: String },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -63,28 +150,166 @@ pub struct PatchUserField {
     pub value: Option<String>,
 }
 
+/// Each code sent within the window doubles the wait before the next one, up to `MAX_OTP_COOLDOWN`.
+fn otp_status(sends: i64, created_at: u64, lifetime: u64) -> OtpStatus {
+    let sends = sends.max(1);
+    let cooldown = OTP_COOLDOWN.saturating_mul(1 << (sends - 1).min(16)).min(MAX_OTP_COOLDOWN);
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    let elapsed = now.saturating_sub(created_at);
+    OtpStatus {
+        attempt_id: None,
+        retry_after: cooldown.saturating_sub(elapsed),
+        expires_in: lifetime.saturating_sub(elapsed),
+    }
+}
+
+/// Whether the checks counted within the window for the email and for the IP lock the login out.
+fn is_locked_out(email_failures: i64, ip_failures: Option<i64>) -> bool {
+    email_failures > MAX_OTP_FAILURES_PER_EMAIL || ip_failures.is_some_and(|ip_failures| ip_failures > MAX_OTP_FAILURES_PER_IP)
+}
+
+/// Whether a code is used up after a check: it was right, or that was the last attempt it allows.
+fn is_otp_spent(attempts: i64, valid: bool) -> bool {
+    valid || attempts >= MAX_OTP_ATTEMPTS
+}
+
 impl ImplUserService {
     pub fn new(storage: Arc<Storage>) -> Self {
         ImplUserService { storage }
     }
 
-    fn create_jwt_token(&self, email: &str) -> String {
+    fn create_jwt_token(&self, user_id: i64, session_id: i64) -> String {
         let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
         let claims = JwtClaims {
-            sub: email.to_string(),
-            exp: SystemTime::now()
-                .checked_add(Duration::from_secs(3600)).unwrap()
-                .duration_since(SystemTime::UNIX_EPOCH).unwrap()
-                .as_secs() as usize,
+            sub: user_id.to_string(),
+            exp: now.checked_add(Duration::from_secs(TOKEN_LIFETIME)).unwrap().as_secs() as usize,
+            iat: now.as_secs() as usize,
+            sid: session_id,
         };
         let token = jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &EncodingKey::from_secret(secret.as_ref())).unwrap();
         token
     }
 
-    fn verify_jwt_token(&self, token: &str) -> Option<String> {
+    fn verify_jwt_token(&self, token: &str) -> Option<JwtClaims> {
         let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
         let token_data = jsonwebtoken::decode::<JwtClaims>(token, &jsonwebtoken::DecodingKey::from_secret(secret.as_ref()), &jsonwebtoken::Validation::default()).ok()?;
-        Some(token_data.claims.sub)
+        Some(token_data.claims)
+    }
+
+    /// Issues an access token with a fresh refresh token of the same session.
+    async fn create_tokens(&self, user_id: i64, session_id: i64) -> Result<Tokens, UserServiceError> {
+        let refresh_token = random::random_word(64);
+        self.storage.create_refresh_token(session_id, &random::sha256(&refresh_token), REFRESH_TOKEN_LIFETIME).await?;
+        Ok(Tokens { access_token: self.create_jwt_token(user_id, session_id), refresh_token })
+    }
+
+    /// Where the login code of the email stands, see `otp_status`.
+    async fn get_otp_status(&self, email: &str) -> Result<Option<OtpStatus>, UserServiceError> {
+        let Some(stored_otp) = self.storage.get_otp(email).await? else {
+            return Ok(None);
+        };
+        let sends = self.storage.get_otp_sends(email).await?;
+        Ok(Some(otp_status(sends, stored_otp.created_at, OTP_LIFETIME)))
+    }
+
+    /// Same cooldown as login codes, counted per user. Nothing is pending once the change expired.
+    async fn get_email_change_status(&self, user_id: i64) -> Result<Option<OtpStatus>, UserServiceError> {
+        let Some(email_change) = self.storage.get_email_change(user_id).await? else {
+            return Ok(None);
+        };
+        let sends = self.storage.get_email_change_sends(user_id).await?;
+        Ok(Some(otp_status(sends, email_change.created_at, EMAIL_CHANGE_LIFETIME)))
+    }
+
+    async fn create_otp<T: EmailService + Send + Sync>(&self, email: &str, email_service: &T) -> Result<OtpStatus, UserServiceError> {
+        let otp = random::generate_otp();
+        let salt = random::random_word(16);
+        let attempt_id = random::random_word(32);
+        self.storage.delete_otp(email).await?;
+        self.storage.store_otp(email, &random::hash_otp(&salt, &otp), &salt, &random::sha256(&attempt_id), OTP_LIFETIME).await?;
+        self.storage.add_otp_send(email, OTP_SENDS_WINDOW).await?;
+        email_service.send_otp(email, &otp, OTP_LIFETIME).await?;
+        let otp_status = self.get_otp_status(email).await?.ok_or(UserServiceError::OTPNotSent)?;
+        Ok(OtpStatus { attempt_id: Some(attempt_id), ..otp_status })
+    }
+
+    /// Checks the code sent to the email and uses it up. A code dies after a few wrong guesses,
+    /// and an email or IP with too many failures is locked out for a while.
+    /// Every check is counted before comparing and taken back if it was right.
+    async fn check_otp(&self, email: &str, otp: &str, attempt_id: &str, ip: Option<&str>) -> Result<(), UserServiceError> {
+        let email_key = format!("email:{}", email);
+        let ip_key = ip.map(|ip| format!("ip:{}", ip));
+        let email_failures = self.storage.add_otp_failure(&email_key, OTP_FAILURES_WINDOW).await?;
+        let ip_failures = match &ip_key {
+            Some(ip_key) => Some(self.storage.add_otp_failure(ip_key, OTP_FAILURES_WINDOW).await?),
+            None => None,
+        };
+        if is_locked_out(email_failures, ip_failures) {
+            return Err(UserServiceError::TooManyAttempts);
+        }
+        let stored_otp = self.storage.get_otp(email).await?;
+        let Some(stored_otp) = stored_otp else {
+            return Err(UserServiceError::InvalidOTP);
+        };
+        let attempts = self.storage.add_otp_attempt(email).await?;
+        let valid_otp = random::constant_time_eq(&random::hash_otp(&stored_otp.salt, otp), &stored_otp.otp_hash);
+        let valid_attempt = random::constant_time_eq(&random::sha256(attempt_id), &stored_otp.attempt_hash);
+        let valid = attempts <= MAX_OTP_ATTEMPTS && valid_otp && valid_attempt;
+        if is_otp_spent(attempts, valid) {
+            self.storage.delete_otp(email).await?;
+        }
+        if !valid {
+            return Err(UserServiceError::InvalidOTP);
+        }
+        self.storage.remove_otp_failure(&email_key).await?;
+        if let Some(ip_key) = &ip_key {
+            self.storage.remove_otp_failure(ip_key).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the pending login with the user it is for.
+    async fn get_pending_login(&self, password_token: &str) -> Result<(PendingLogin, User), UserServiceError> {
+        let pending_login = self.storage.get_pending_login(&random::sha256(password_token)).await?;
+        let pending_login = pending_login.ok_or(UserServiceError::InvalidAuthentication)?;
+        let user = self.storage.get_user_by_email(&pending_login.email).await?;
+        let user = user.ok_or(UserServiceError::InvalidAuthentication)?;
+        Ok((pending_login, user))
+    }
+
+    async fn finish_pending_login(&self, password_token: &str, pending_login: &PendingLogin, user_id: i64) -> Result<Tokens, UserServiceError> {
+        self.storage.delete_pending_login(&random::sha256(password_token)).await?;
+        self.start_session(user_id, pending_login).await
+    }
+
+    async fn start_session(&self, user_id: i64, pending_login: &PendingLogin) -> Result<Tokens, UserServiceError> {
+        let new_session = NewSession {
+            device_name: pending_login.device_name.as_deref(),
+            ip: pending_login.ip.as_deref(),
+            user_agent: pending_login.user_agent.as_deref(),
+        };
+        let session = self.storage.create_session(user_id, &new_session).await?;
+        self.create_tokens(user_id, session.id).await
+    }
+
+    /// Returns who a valid token belongs to while its session is still alive, and marks the session active.
+    async fn verify_token(&self, token: &str) -> Result<Option<TokenOwner>, UserServiceError> {
+        let Some(claims) = self.verify_jwt_token(token) else {
+            return Ok(None);
+        };
+        let Ok(user_id) = claims.sub.parse::<i64>() else {
+            return Ok(None);
+        };
+        let Some(session) = self.storage.get_session(claims.sid, user_id).await? else {
+            return Ok(None);
+        };
+        // Written at most once a minute, so busy clients don't turn every request into an update.
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as usize;
+        if session.last_active_at + 60 < now {
+            self.storage.touch_session(session.id).await?;
+        }
+        Ok(Some(TokenOwner { user_id, session_id: session.id }))
     }
 
     fn check_username(&self, username: &str) -> Result<(), UserServiceError> {
@@ -99,68 +324,158 @@ impl ImplUserService {
         }
         Ok(())
     }
+
+    /// Checks a profile field and sets it on the user. Values are trimmed and an empty value clears the field,
+    /// except for the first name, which is required.
+    fn apply_field(&self, user: &mut User, name: &str, value: Option<String>) -> Result<(), UserServiceError> {
+        let value = value.map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+        let length = value.as_deref().map_or(0, |value| value.chars().count());
+        let (valid, field) = match name {
+            "first_name" => {
+                let first_name = value.filter(|_| length <= MAX_NAME_LENGTH);
+                user.first_name = first_name.ok_or_else(|| UserServiceError::InvalidField(name.to_string()))?;
+                return Ok(());
+            }
+            "last_name" => (length <= MAX_NAME_LENGTH, &mut user.last_name),
+            "username" => (true, &mut user.username),
+            "bio" => (length <= MAX_BIO_LENGTH, &mut user.bio),
+            "birthday" => (value.as_deref().is_none_or(|birthday| chrono::NaiveDate::parse_from_str(birthday, "%Y-%m-%d")
+                .is_ok_and(|birthday| birthday.year() >= 1900 && birthday <= chrono::Utc::now().date_naive())), &mut user.birthday),
+            "website" => (value.as_deref().is_none_or(|website| website.len() <= MAX_WEBSITE_LENGTH && entities::is_valid_url(website)), &mut user.website),
+            "status_text" => (length <= MAX_STATUS_TEXT_LENGTH, &mut user.status_text),
+            "status_emoji" => (length <= MAX_STATUS_EMOJI_LENGTH && value.as_deref().is_none_or(entities::is_emoji), &mut user.status_emoji),
+            _ => return Err(UserServiceError::UnknownField(name.to_string())),
+        };
+        if !valid {
+            return Err(UserServiceError::InvalidField(name.to_string()));
+        }
+        *field = value;
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
 impl UserService for ImplUserService {
-    async fn send_otp<T: EmailService + Send + Sync>(&self, email: &str, email_service: &T) -> Result<String, UserServiceError> {
+    /// Sends nothing while a code is still valid, and tells when a new one can be asked for instead.
+    async fn send_otp<T: EmailService + Send + Sync>(&self, email: &str, email_service: &T) -> Result<OtpStatus, UserServiceError> {
         if email.is_empty() || !email.contains('@') {
             return Err(UserServiceError::InvalidEmail);
         }
-        let stored_otp = self.storage.get_otp(email).await?;
-        if stored_otp.is_some() {
-            return Err(UserServiceError::OTPNotSent);
+        if let Some(otp_status) = self.get_otp_status(email).await? {
+            return Ok(otp_status);
         }
-        let otp = random::generate_otp();
-        self.storage.store_otp(email, &otp).await?;
-        email_service.send_otp(&email, &otp).await?;
-        let otp_hash = random::random_hash(&otp);
-        Ok(otp_hash)
+        self.create_otp(email, email_service).await
     }
 
-    async fn verify_otp(&self, email: &str, otp: &str) -> Result<String, UserServiceError> {
+    /// Replaces the current code with a new one once the cooldown is over.
+    async fn resend_otp<T: EmailService + Send + Sync>(&self, email: &str, email_service: &T) -> Result<OtpStatus, UserServiceError> {
         if email.is_empty() || !email.contains('@') {
             return Err(UserServiceError::InvalidEmail);
         }
-        let stored_otp = self.storage.get_otp(email).await?;
-        if stored_otp.is_none() {
-            return Err(UserServiceError::InvalidOTP);
+        let otp_status = self.get_otp_status(email).await?.ok_or(UserServiceError::OTPNotSent)?;
+        if otp_status.retry_after > 0 {
+            return Ok(otp_status);
         }
-        let (stored_otp, _) = stored_otp.unwrap();
-        if stored_otp != otp {
-            return Err(UserServiceError::InvalidOTP);
+        self.create_otp(email, email_service).await
+    }
+
+    async fn verify_otp(&self, email: &str, otp: &str, attempt_id: &str, new_session: &NewSession<'_>) -> Result<Login, UserServiceError> {
+        if email.is_empty() || !email.contains('@') {
+            return Err(UserServiceError::InvalidEmail);
         }
-        self.storage.delete_otp(email).await?;
-        Ok(self.create_jwt_token(email))
+        self.check_otp(email, otp, attempt_id, new_session.ip).await?;
+        let pending_login = PendingLogin {
+            email: email.to_string(),
+            device_name: new_session.device_name.map(str::to_string),
+            ip: new_session.ip.map(str::to_string),
+            user_agent: new_session.user_agent.map(str::to_string),
+        };
+        let Some(user) = self.storage.get_user_by_email(email).await? else {
+            let signup_token = random::random_word(64);
+            self.storage.store_pending_signup(&random::sha256(&signup_token), &pending_login, SIGNUP_LIFETIME).await?;
+            return Ok(Login::SignupRequired { signup_token });
+        };
+        let password_service = ImplPasswordService::new(self.storage.clone());
+        let password_info = password_service.get_password_info(user.id).await?;
+        if password_info.has_password {
+            let password_token = random::random_word(64);
+            self.storage.store_pending_login(&random::sha256(&password_token), &pending_login).await?;
+            return Ok(Login::PasswordRequired { password_token, hint: password_info.hint });
+        }
+        let session = self.storage.create_session(user.id, new_session).await?;
+        Ok(Login::Tokens(self.create_tokens(user.id, session.id).await?))
+    }
+
+    async fn verify_password(&self, password_token: &str, password: &str) -> Result<Tokens, UserServiceError> {
+        let (pending_login, user) = self.get_pending_login(password_token).await?;
+        let password_service = ImplPasswordService::new(self.storage.clone());
+        password_service.check_password(user.id, password).await?;
+        self.finish_pending_login(password_token, &pending_login, user.id).await
+    }
+
+    async fn send_password_recovery<T: EmailService + Send + Sync>(&self, password_token: &str, email_service: &T) -> Result<(), UserServiceError> {
+        let (_, user) = self.get_pending_login(password_token).await?;
+        let password_service = ImplPasswordService::new(self.storage.clone());
+        password_service.send_recovery_otp(user.id, email_service).await?;
+        Ok(())
+    }
+
+    /// Removes the forgotten password and logs in.
+    async fn recover_password(&self, password_token: &str, otp: &str) -> Result<Tokens, UserServiceError> {
+        let (pending_login, user) = self.get_pending_login(password_token).await?;
+        let password_service = ImplPasswordService::new(self.storage.clone());
+        password_service.recover_password(user.id, otp).await?;
+        self.finish_pending_login(password_token, &pending_login, user.id).await
+    }
+
+    /// Refresh tokens are single-use. Presenting a used one means it leaked,
+    /// so the whole session is terminated along with every token issued for it.
+    async fn refresh_tokens(&self, refresh_token: &str, event_service: &EventService) -> Result<Tokens, UserServiceError> {
+        let token_hash = random::sha256(refresh_token);
+        let Some((session_id, user_id)) = self.storage.use_refresh_token(&token_hash).await? else {
+            if let Some(session_id) = self.storage.revoke_refresh_token_family(&token_hash).await? {
+                event_service.close_sessions(&[session_id]).await;
+            }
+            return Err(UserServiceError::InvalidAuthentication);
+        };
+        self.storage.touch_session(session_id).await?;
+        self.create_tokens(user_id, session_id).await
     }
 
     async fn authenticate(&self, token: &str) -> Result<Option<User>, UserServiceError> {
-        if let Some(email) = self.verify_jwt_token(token) {
-            let user = self.storage.get_user_by_email(&email).await?;
+        if let Some(owner) = self.verify_token(token).await? {
+            let user = self.storage.get_user(owner.user_id).await?;
             return Ok(user);
         }
         Err(UserServiceError::InvalidAuthentication)
     }
 
-    async fn create_user(&self, token: &str, first_name: &str, last_name: Option<&str>) -> Result<User, UserServiceError> {
-        let email = self.verify_jwt_token(token).ok_or(UserServiceError::InvalidAuthentication)?;
-        let user = self.storage.get_user_by_email(&email).await?;
-        if user.is_some() {
+    async fn authenticate_session(&self, token: &str) -> Result<(User, i64), UserServiceError> {
+        let owner = self.verify_token(token).await?.ok_or(UserServiceError::InvalidAuthentication)?;
+        let user = self.storage.get_user(owner.user_id).await?;
+        let user = user.ok_or(UserServiceError::InvalidAuthentication)?;
+        Ok((user, owner.session_id))
+    }
+
+    /// The account is created with the signup token `verify_otp` gave for a new email,
+    /// and its first session is started right away.
+    async fn create_user(&self, signup_token: &str, first_name: &str, last_name: Option<&str>) -> Result<(User, Tokens), UserServiceError> {
+        let token_hash = random::sha256(signup_token);
+        let pending_signup = self.storage.get_pending_signup(&token_hash).await?;
+        let pending_signup = pending_signup.ok_or(UserServiceError::InvalidAuthentication)?;
+        if self.storage.get_user_by_email(&pending_signup.email).await?.is_some() {
             return Err(UserServiceError::UserAlreadyExists);
         }
-        let user = self.storage.create_user(&email, None, first_name, last_name).await?;
-        Ok(user)
+        let user = self.storage.create_user(&pending_signup.email, None, first_name, last_name).await?;
+        self.storage.delete_pending_signup(&token_hash).await?;
+        let tokens = self.start_session(user.id, &pending_signup).await?;
+        Ok((user, tokens))
     }
 
     async fn patch_me(&self, token: &str, fields: Vec<PatchUserField>) -> Result<User, UserServiceError> {
         let mut user = self.authenticate_with_user(token).await?;
         for field in fields {
-            match field.name.as_str() {
-                "first_name" => user.first_name = field.value.unwrap_or_default(),
-                "last_name" => user.last_name = field.value,
-                "username" => user.username = field.value,
-                _ => (),
-            }
+            self.apply_field(&mut user, &field.name, field.value)?;
         }
         if let Some(username) = &user.username {
             self.check_username(username)?;
@@ -172,4 +487,164 @@ impl UserService for ImplUserService {
         self.storage.update_user(&user).await?;
         Ok(user)
     }
+
+    /// Needs a fresh OTP sent to the account email, so a stolen token alone can't delete the account.
+    async fn delete_me<T: PhotoService + Send + Sync>(&self, token: &str, otp: &str, attempt_id: &str, delete_messages: bool, photo_service: &T, event_service: &EventService) -> Result<(), UserServiceError> {
+        let user = self.authenticate_with_user(token).await?;
+        let email = user.email.clone().ok_or(UserServiceError::InvalidAuthentication)?;
+        if self.storage.get_otp(&email).await?.is_none() {
+            return Err(UserServiceError::OTPNotSent);
+        }
+        self.check_otp(&email, otp, attempt_id, None).await?;
+        // Blocks are deleted along with the account, so blocked users are left out beforehand.
+        let mut watchers = Vec::new();
+        for watcher in self.storage.get_watchers(user.id).await? {
+            if !self.storage.is_blocked(user.id, watcher).await? {
+                watchers.push(watcher);
+            }
+        }
+        let deleted_account = self.storage.delete_account(user.id, delete_messages).await?;
+        event_service.close_sessions(&deleted_account.session_ids).await;
+        // Files only go once the rows are committed, so a failed deletion leaves the account whole.
+        // The account is gone by now either way, so a file that can't be removed is only logged.
+        if let Err(photo_error) = photo_service.delete_photo_files(&deleted_account.photo_ids).await {
+            log::error!("Photos of deleted user {} weren't removed: {:?}", user.id, photo_error);
+        }
+        for deleted_message in deleted_account.messages {
+            event_service.notify_chat(deleted_message.from_id, deleted_message.chat_id, BackendEvent::MessageDeleted(deleted_message.clone())).await;
+        }
+        if let Some(deleted_user) = self.storage.get_user_or_deleted(user.id).await? {
+            for watcher in watchers {
+                if watcher != user.id {
+                    event_service.notify(watcher, BackendEvent::UserUpdated(deleted_user.clone())).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a code to both addresses: the new one proves ownership, the old one that the owner asked for it.
+    /// A pending change is only replaced once its cooldown is over, and both the user and the new address
+    /// get at most `MAX_OTP_SENDS` codes per window, so this can't be used to flood someone's inbox.
+    async fn request_email_change<T: EmailService + Send + Sync>(&self, token: &str, new_email: &str, email_service: &T) -> Result<OtpStatus, UserServiceError> {
+        let user = self.authenticate_with_user(token).await?;
+        let old_email = user.email.clone().ok_or(UserServiceError::InvalidAuthentication)?;
+        if new_email.is_empty() || !new_email.contains('@') || new_email == old_email {
+            return Err(UserServiceError::InvalidEmail);
+        }
+        if self.storage.get_user_by_email(new_email).await?.is_some() {
+            return Err(UserServiceError::EmailUsed);
+        }
+        if let Some(email_change_status) = self.get_email_change_status(user.id).await?
+            && email_change_status.retry_after > 0
+        {
+            return Ok(email_change_status);
+        }
+        if self.storage.get_email_change_sends(user.id).await? >= MAX_OTP_SENDS || self.storage.get_otp_sends(new_email).await? >= MAX_OTP_SENDS {
+            return Err(UserServiceError::TooManyAttempts);
+        }
+        let old_otp = random::generate_otp();
+        let new_otp = random::generate_otp();
+        let email_change = EmailChange {
+            new_email: new_email.to_string(),
+            old_otp: HashedOtp::new(&old_otp),
+            new_otp: HashedOtp::new(&new_otp),
+            created_at: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+        };
+        self.storage.delete_email_change(user.id).await?;
+        self.storage.store_email_change(user.id, &email_change, EMAIL_CHANGE_LIFETIME).await?;
+        self.storage.add_email_change_send(user.id, OTP_SENDS_WINDOW).await?;
+        self.storage.add_otp_send(new_email, OTP_SENDS_WINDOW).await?;
+        email_service.send_otp(&old_email, &old_otp, EMAIL_CHANGE_LIFETIME).await?;
+        email_service.send_otp(new_email, &new_otp, EMAIL_CHANGE_LIFETIME).await?;
+        self.get_email_change_status(user.id).await?.ok_or(UserServiceError::OTPNotSent)
+    }
+
+    /// Every other session is terminated; the current one stays.
+    /// The change is dropped after a few wrong guesses and has to be requested again.
+    async fn confirm_email_change<T: EmailService + Send + Sync>(&self, token: &str, old_otp: &str, new_otp: &str, email_service: &T, event_service: &EventService) -> Result<User, UserServiceError> {
+        let (mut user, session_id) = self.authenticate_session(token).await?;
+        let old_email = user.email.clone().ok_or(UserServiceError::InvalidAuthentication)?;
+        let Some(email_change) = self.storage.get_email_change(user.id).await? else {
+            return Err(UserServiceError::OTPNotSent);
+        };
+        let attempts = self.storage.add_email_change_attempt(user.id).await?;
+        if attempts > MAX_OTP_ATTEMPTS {
+            self.storage.delete_email_change(user.id).await?;
+            return Err(UserServiceError::InvalidOTP);
+        }
+        let valid_old_otp = email_change.old_otp.matches(old_otp);
+        let valid_new_otp = email_change.new_otp.matches(new_otp);
+        if !valid_old_otp || !valid_new_otp {
+            if attempts == MAX_OTP_ATTEMPTS {
+                self.storage.delete_email_change(user.id).await?;
+            }
+            return Err(UserServiceError::InvalidOTP);
+        }
+        self.storage.delete_email_change(user.id).await?;
+        if !self.storage.update_email(user.id, &email_change.new_email).await? {
+            return Err(UserServiceError::EmailUsed);
+        }
+        let deleted_sessions = self.storage.delete_sessions(user.id, Some(session_id)).await?;
+        event_service.close_sessions(&deleted_sessions).await;
+        email_service.send_email_changed(&old_email, &email_change.new_email).await?;
+        user.email = Some(email_change.new_email);
+        Ok(user)
+    }
+
+    async fn get_sessions(&self, token: &str) -> Result<Vec<Session>, UserServiceError> {
+        let owner = self.verify_token(token).await?.ok_or(UserServiceError::InvalidAuthentication)?;
+        let mut sessions = self.storage.get_sessions(owner.user_id).await?;
+        for session in &mut sessions {
+            session.is_current = session.id == owner.session_id;
+        }
+        Ok(sessions)
+    }
+
+    /// Terminating the current session is how a client logs out.
+    async fn terminate_session(&self, token: &str, session_id: i64, event_service: &EventService) -> Result<(), UserServiceError> {
+        let owner = self.verify_token(token).await?.ok_or(UserServiceError::InvalidAuthentication)?;
+        if !self.storage.delete_session(session_id, owner.user_id).await? {
+            return Err(UserServiceError::SessionNotFound);
+        }
+        event_service.close_sessions(&[session_id]).await;
+        Ok(())
+    }
+
+    async fn terminate_other_sessions(&self, token: &str, event_service: &EventService) -> Result<(), UserServiceError> {
+        let owner = self.verify_token(token).await?.ok_or(UserServiceError::InvalidAuthentication)?;
+        let deleted_sessions = self.storage.delete_sessions(owner.user_id, Some(owner.session_id)).await?;
+        event_service.close_sessions(&deleted_sessions).await;
+        Ok(())
+    }
+
+    async fn delete_expired_sessions(&self, event_service: &EventService) -> Result<usize, UserServiceError> {
+        let deleted_sessions = self.storage.delete_expired_sessions(NEW_SESSION_GRACE).await?;
+        event_service.close_sessions(&deleted_sessions).await;
+        Ok(deleted_sessions.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_locked_out_counts_failures_per_email_and_ip() {
+        let locked: Vec<bool> = (1..=MAX_OTP_FAILURES_PER_EMAIL + 1).map(|failures| is_locked_out(failures, None)).collect();
+        assert_eq!(locked.iter().filter(|locked| **locked).count(), 1);
+        assert!(locked[MAX_OTP_FAILURES_PER_EMAIL as usize]);
+        assert!(!is_locked_out(1, Some(MAX_OTP_FAILURES_PER_IP)));
+        assert!(is_locked_out(1, Some(MAX_OTP_FAILURES_PER_IP + 1)));
+    }
+
+    #[test]
+    fn is_otp_spent_after_the_last_attempt() {
+        for attempts in 1..MAX_OTP_ATTEMPTS {
+            assert!(!is_otp_spent(attempts, false));
+        }
+        assert!(is_otp_spent(MAX_OTP_ATTEMPTS, false));
+        assert!(is_otp_spent(MAX_OTP_ATTEMPTS + 1, false));
+        assert!(is_otp_spent(1, true));
+    }
 }
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use log::error;
+
+use crate::{api::AppState, services::{email::{EmailService, ImplEmailService}, events::EventService, message::{ImplMessageService, MessageService}, presence::{ImplPresenceService, PresenceService}, preview::PreviewService, user::{ImplUserService, UserService}}};
+
+/// Delivers scheduled messages once they are due. The queue lives in Postgres, so nothing is lost on restart.
+pub fn spawn_scheduled_messages(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            let message_service = ImplMessageService::new(state.storage.clone());
+            let event_service = EventService::new(state.listener_pool.clone());
+            match message_service.send_due_scheduled_messages(&event_service).await {
+                Ok(messages) => {
+                    for message in messages {
+                        let preview_service = PreviewService::new(state.storage.clone(), state.link_preview_fetcher.clone());
+                        preview_service.spawn_attach_preview(message, EventService::new(state.listener_pool.clone()));
+                    }
+                },
+                Err(message_error) => error!("Message Service Error: {:?}", message_error),
+            }
+        }
+    });
+}
+
+/// Persists presence changes reported by the listener pool and pushes them to chat partners.
+pub fn spawn_presence(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            for presence_change in state.listener_pool.take_presence_changes().await {
+                let presence_service = ImplPresenceService::new(state.storage.clone());
+                let event_service = EventService::new(state.listener_pool.clone());
+                if let Err(presence_error) = presence_service.update_presence(presence_change, &event_service).await {
+                    error!("Presence Service Error: {:?}", presence_error);
+                }
+            }
+        }
+    });
+}
+
+/// Writes view counters collected in Redis to Postgres.
+pub fn spawn_views_flusher(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        loop {
+            interval.tick().await;
+            let message_service = ImplMessageService::new(state.storage.clone());
+            if let Err(message_error) = message_service.flush_views().await {
+                error!("Message Service Error: {:?}", message_error);
+            }
+        }
+    });
+}
+
+/// Purges messages whose timer ran out and tells both sides of the chat.
+pub fn spawn_expired_messages_sweeper(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            let message_service = ImplMessageService::new(state.storage.clone());
+            let event_service = EventService::new(state.listener_pool.clone());
+            if let Err(message_error) = message_service.delete_expired_messages(&event_service).await {
+                error!("Message Service Error: {:?}", message_error);
+            }
+        }
+    });
+}
+
+/// Deletes sessions that can't be refreshed anymore and ends their event streams.
+pub fn spawn_expired_sessions_sweeper(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            let user_service = ImplUserService::new(state.storage.clone());
+            let event_service = EventService::new(state.listener_pool.clone());
+            if let Err(user_error) = user_service.delete_expired_sessions(&event_service).await {
+                error!("User Service Error: {:?}", user_error);
+            }
+        }
+    });
+}
+
+/// Delivers queued emails from the outbox, retrying failed ones with backoff.
+pub fn spawn_email_outbox(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            let email_service = ImplEmailService::new(state.storage.clone());
+            if let Err(email_error) = email_service.deliver_outbox(state.email_transport.as_ref()).await {
+                error!("Email Service Error: {:?}", email_error);
+            }
+        }
+    });
+}